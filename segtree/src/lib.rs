@@ -23,9 +23,7 @@ impl<T : Copy, F: Fn(&T, &T) -> T> SegmentTree<T, F> {
         let size = value.len().next_power_of_two();
         let log = size.trailing_zeros() as usize;
         let mut data = vec![id; 2 * size];
-        for i in 0..n {
-            data[size + i] = value[i];
-        }
+        data[size..size + n].copy_from_slice(value);
         let mut seg = Self { n, size, log, data, id, op };
         for i in (1..size).rev() {
             seg.update(i);
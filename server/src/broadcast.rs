@@ -0,0 +1,132 @@
+use crate::fault::{FaultInjector, Faults};
+use std::io::Write;
+use std::net::TcpStream;
+use std::sync::mpsc::{sync_channel, SyncSender, TrySendError};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+// 1 クライアントあたりに溜めておくフレーム数（溢れた分は捨てる）
+const QUEUE_SIZE: usize = 256;
+
+struct Client {
+    tx: SyncSender<Arc<Vec<u8>>>,
+    // 1 フレームでも渡したか
+    received: bool,
+    // キューが溢れて捨てたフレーム数（切断時に表示する）
+    dropped: Arc<AtomicUsize>,
+}
+
+#[derive(Default)]
+struct Clients {
+    clients: Vec<Client>,
+    running: bool,
+}
+
+// 共有の再生を接続中のクライアント全員に配る
+#[derive(Default)]
+pub(crate) struct Broadcaster {
+    clients: Mutex<Clients>,
+}
+
+impl Broadcaster {
+    // クライアントを登録する。再生が動いていなければ true を返すので、呼び出し側で再生を始める
+    pub fn subscribe(&self, mut stream: TcpStream, faults: Faults) -> bool {
+        let (tx, rx) = sync_channel::<Arc<Vec<u8>>>(QUEUE_SIZE);
+        let dropped = Arc::new(AtomicUsize::new(0));
+        let counter = dropped.clone();
+        thread::spawn(move || {
+            let addr = stream.peer_addr().map_or("-".into(), |x| x.to_string());
+            let mut injector = FaultInjector::new(faults);
+            let result = rx.iter().try_for_each(|frame| injector.send(&frame, |x| Ok(stream.write_all(x)?)));
+            // 再生が終わって閉じられたときは、遅延中に溜めた分も送る
+            if result.is_ok() {
+                let _ = injector.flush(|x| Ok(stream.write_all(x)?));
+            }
+            match counter.load(Ordering::Relaxed) {
+                0 => println!("Disconnected {addr}"),
+                dropped => println!("Disconnected {addr} ({dropped} frame(s) dropped)"),
+            }
+        });
+
+        let mut clients = self.clients.lock().unwrap();
+        clients.clients.push(Client { tx, received: false, dropped });
+        !std::mem::replace(&mut clients.running, true)
+    }
+
+    pub fn send(&self, frame: Vec<u8>) {
+        let frame = Arc::new(frame);
+        self.clients.lock().unwrap().clients.retain_mut(|client| match client.tx.try_send(frame.clone()) {
+            Ok(()) => {
+                client.received = true;
+                true
+            }
+            Err(TrySendError::Full(_)) => {
+                client.received = true;
+                client.dropped.fetch_add(1, Ordering::Relaxed);
+                true
+            }
+            Err(TrySendError::Disconnected(_)) => false,
+        });
+    }
+
+    // 再生が終わったら、フレームを受け取ったクライアントを切断する
+    // 再生の終わり際に来てまだ何も受け取っていないクライアントが残っていれば、再生を続けたまま true を返すので、呼び出し側で最初から再生し直す
+    pub fn finish(&self, restart: bool) -> bool {
+        let mut clients = self.clients.lock().unwrap();
+        clients.clients.retain(|client| restart && !client.received);
+        clients.running = !clients.clients.is_empty();
+        clients.running
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+    use std::net::TcpListener;
+
+    // 接続して、サーバー側とクライアント側のソケットを返す
+    fn connect(listener: &TcpListener) -> (TcpStream, TcpStream) {
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        (listener.accept().unwrap().0, client)
+    }
+
+    fn read_all(mut stream: TcpStream) -> Vec<u8> {
+        let mut buf = Vec::new();
+        stream.read_to_end(&mut buf).unwrap();
+        buf
+    }
+
+    #[test]
+    fn restarts_for_client_that_joined_at_the_end() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let broadcaster = Broadcaster::default();
+        let (server, first) = connect(&listener);
+        assert!(broadcaster.subscribe(server, Faults::default()));
+        broadcaster.send(vec![1, 2]);
+
+        // 最後のフレームの後、finish の前に来たクライアント
+        let (server, late) = connect(&listener);
+        assert!(!broadcaster.subscribe(server, Faults::default()));
+        assert!(broadcaster.finish(true));
+        assert_eq!(read_all(first), [1, 2]);
+
+        broadcaster.send(vec![3]);
+        assert!(!broadcaster.finish(true));
+        assert_eq!(read_all(late), [3]);
+
+        let (server, _) = connect(&listener);
+        assert!(broadcaster.subscribe(server, Faults::default()));
+    }
+
+    #[test]
+    fn does_not_restart_after_empty_replay() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let broadcaster = Broadcaster::default();
+        let (server, client) = connect(&listener);
+        assert!(broadcaster.subscribe(server, Faults::default()));
+        assert!(!broadcaster.finish(false));
+        assert_eq!(read_all(client), []);
+    }
+}
@@ -0,0 +1,216 @@
+use crate::fault::Faults;
+use crate::pacing::{parse_duration, Pacing};
+use chrono::{Datelike, NaiveDate};
+use server::frame::CURRENT_VERSION;
+use server::observation_points::ObservationPoint;
+use server::PORT;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::path::PathBuf;
+use std::str::FromStr;
+
+const USAGE: &str = "\
+Usage: server [OPTIONS]
+
+Options:
+  --bind <ADDR>              待ち受けるアドレス [default: 127.0.0.1:6051]
+  --data-dir <DIR>           observation.csv と地点ごとの CSV を置いたディレクトリ [default: data]
+  --source <SOURCE>          csv: data-dir の CSV を送る / synthetic: それらしい気温を生成して送る [default: csv]
+  --seed <N>                 synthetic の乱数のシード [default: 0]
+  --events                   synthetic で熱波・寒波を入れる
+  --start <YYYY-MM-DD>       この日から送る
+  --end <YYYY-MM-DD>         この日まで送る
+  --stations <LIST>          送る地点（地点番号または都府県名のカンマ区切り）
+  --decades <LIST>           読み込む年（10 年単位のカンマ区切り） [default: ある分すべて]
+  --speed <X>                再生速度の倍率 (1.0 = 1 日 32 ms) [default: 1.0]
+  --pace <MODE>              fast: --speed の間隔で送る
+                             years-ago:<N>: N 年前の今日のデータを、日付が変わるごとに送る
+                             year-per:<DURATION>: 1 年分を実時間の DURATION (30s, 10m, 1h, 2d など) で送る
+                             [default: fast]
+  --protocol-version <N>     送信するフレームのバージョン [default: 最新]
+  --control <ADDR>           制御コマンドを 1 行ずつ受け付けるアドレス（例: 127.0.0.1:6052）
+                             pause / resume / seek <YYYY-MM-DD> / speed <X> / status
+  --clock <MODE>             per-client: 接続ごとに最初から再生する / shared: 全員が同じ時刻を見る [default: per-client]
+  --fault-drop <P>           フレームを確率 P で捨てる
+  --fault-duplicate <P>      フレームを確率 P で 2 回送る
+  --fault-truncate <P>       フレームを確率 P で途中までしか送らない
+  --fault-delay <P>          確率 P で送るのを止め、溜めたフレームをまとめて送る
+  --fault-delay-duration <DURATION>
+                             --fault-delay で止める長さ [default: 500ms]
+  --fault-disconnect <P>     フレームを送る前に確率 P で接続を切る
+  --fault-seed <N>           障害を起こす乱数のシード（接続ごとに同じ順番で起こる） [default: 0]
+  --loop                     最後まで送ったら最初に戻って繰り返す
+  -h, --help                 このヘルプを表示する";
+
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum StationFilter {
+    Id(u32),
+    Prefecture(String),
+}
+
+impl StationFilter {
+    fn parse(s: &str) -> Self {
+        match s.parse() {
+            Ok(id) => StationFilter::Id(id),
+            Err(_) => StationFilter::Prefecture(s.to_string()),
+        }
+    }
+
+    fn matches(&self, point: &ObservationPoint) -> bool {
+        match self {
+            StationFilter::Id(id) => point.id() == *id,
+            StationFilter::Prefecture(name) => point.prefecture() == name,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Clock {
+    // 接続ごとに最初から再生する
+    PerClient,
+    // 1 本の再生を全員に配る
+    Shared,
+}
+
+impl FromStr for Clock {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "per-client" => Ok(Clock::PerClient),
+            "shared" => Ok(Clock::Shared),
+            _ => Err(anyhow::anyhow!("unknown clock mode: {s}")),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Source {
+    Csv,
+    // 観測地点の緯度・標高から気温を生成する（observation.csv だけあればよい）
+    Synthetic,
+}
+
+impl FromStr for Source {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "csv" => Ok(Source::Csv),
+            "synthetic" => Ok(Source::Synthetic),
+            _ => Err(anyhow::anyhow!("unknown source: {s}")),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub(crate) struct Config {
+    pub bind: SocketAddr,
+    pub data_dir: PathBuf,
+    pub source: Source,
+    pub seed: u64,
+    pub events: bool,
+    pub start: Option<NaiveDate>,
+    pub end: Option<NaiveDate>,
+    // 空ならすべての地点
+    pub stations: Vec<StationFilter>,
+    // 空ならディレクトリにあるすべての年
+    pub decades: Vec<u32>,
+    pub speed: f64,
+    pub pace: Pacing,
+    pub protocol_version: u8,
+    pub clock: Clock,
+    pub control: Option<SocketAddr>,
+    pub r#loop: bool,
+    pub faults: Faults,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            bind: SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, PORT)),
+            data_dir: PathBuf::from("data"),
+            source: Source::Csv,
+            seed: 0,
+            events: false,
+            start: None,
+            end: None,
+            stations: Vec::new(),
+            decades: Vec::new(),
+            speed: 1.0,
+            pace: Pacing::Fast,
+            protocol_version: CURRENT_VERSION,
+            clock: Clock::PerClient,
+            control: None,
+            r#loop: false,
+            faults: Faults::default(),
+        }
+    }
+}
+
+fn parse_list<T: FromStr>(s: &str) -> anyhow::Result<Vec<T>>
+where
+    T::Err: std::error::Error + Send + Sync + 'static,
+{
+    Ok(s.split(',').map(str::parse).collect::<Result<_, _>>()?)
+}
+
+impl Config {
+    pub fn from_args() -> anyhow::Result<Self> {
+        let mut config = Self::default();
+        let mut args = std::env::args().skip(1);
+        while let Some(arg) = args.next() {
+            let mut value = || args.next().ok_or_else(|| anyhow::anyhow!("missing value for {arg}"));
+            match arg.as_str() {
+                "--bind" => config.bind = value()?.parse()?,
+                "--data-dir" => config.data_dir = PathBuf::from(value()?),
+                "--source" => config.source = value()?.parse()?,
+                "--seed" => config.seed = value()?.parse()?,
+                "--events" => config.events = true,
+                "--start" => config.start = Some(value()?.parse()?),
+                "--end" => config.end = Some(value()?.parse()?),
+                "--stations" => config.stations = value()?.split(',').map(StationFilter::parse).collect(),
+                "--decades" => config.decades = parse_list(&value()?)?,
+                "--speed" => {
+                    config.speed = value()?.parse()?;
+                    anyhow::ensure!(config.speed > 0.0, "--speed must be positive");
+                }
+                "--pace" => config.pace = value()?.parse()?,
+                "--protocol-version" => {
+                    config.protocol_version = value()?.parse()?;
+                    anyhow::ensure!(config.protocol_version <= CURRENT_VERSION, "unsupported protocol version: {}", config.protocol_version);
+                }
+                "--clock" => config.clock = value()?.parse()?,
+                "--control" => config.control = Some(value()?.parse()?),
+                "--loop" => config.r#loop = true,
+                "--fault-drop" => config.faults.drop = Faults::parse_probability(&value()?)?,
+                "--fault-duplicate" => config.faults.duplicate = Faults::parse_probability(&value()?)?,
+                "--fault-truncate" => config.faults.truncate = Faults::parse_probability(&value()?)?,
+                "--fault-delay" => config.faults.delay = Faults::parse_probability(&value()?)?,
+                "--fault-delay-duration" => config.faults.delay_duration = parse_duration(&value()?)?,
+                "--fault-disconnect" => config.faults.disconnect = Faults::parse_probability(&value()?)?,
+                "--fault-seed" => config.faults.seed = value()?.parse()?,
+                "-h" | "--help" => {
+                    println!("{USAGE}");
+                    std::process::exit(0);
+                }
+                _ => anyhow::bail!("unknown option: {arg}\n\n{USAGE}"),
+            }
+        }
+        Ok(config)
+    }
+
+    pub fn includes_station(&self, point: &ObservationPoint) -> bool {
+        self.stations.is_empty() || self.stations.iter().any(|x| x.matches(point))
+    }
+
+    // 範囲外の 10 年は読まない
+    pub fn includes_decade(&self, decade: u32) -> bool {
+        (self.decades.is_empty() || self.decades.contains(&decade))
+            && self.start.is_none_or(|x| x.year() <= decade as i32 + 9)
+            && self.end.is_none_or(|x| decade as i32 <= x.year())
+    }
+
+    pub fn includes_date(&self, date: NaiveDate) -> bool {
+        self.start.is_none_or(|x| x <= date) && self.end.is_none_or(|x| date <= x)
+    }
+}
@@ -0,0 +1,112 @@
+use chrono::NaiveDate;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// 1日ごとの時間間隔（ミリ秒）
+const INTERVAL_MILLS: u64 = 32; // 16
+
+struct ControlState {
+    paused: bool,
+    speed: f64,
+    // シークされるたびに増やす。再生側は自分が見た値と比べてシークに気付く
+    seek_generation: u64,
+    seek_to: Option<NaiveDate>,
+}
+
+// 再生の一時停止・再開・シーク・速度変更
+// 制御用ポートに 1 行ずつコマンドを送ると、すべての再生に反映される
+pub(crate) struct Control {
+    state: Mutex<ControlState>,
+    changed: Condvar,
+}
+
+impl Control {
+    pub fn new(speed: f64) -> Self {
+        Self {
+            state: Mutex::new(ControlState { paused: false, speed, seek_generation: 0, seek_to: None }),
+            changed: Condvar::new(),
+        }
+    }
+
+    // これまでにシークされた回数（tick に渡す seen_generation の初期値に使う）
+    pub fn generation(&self) -> u64 {
+        self.state.lock().unwrap().seek_generation
+    }
+
+    // 次のフレームを送るまで待つ。シークされていればその日付を返す
+    // wait が None なら速度に応じた一定間隔だけ待つ
+    // 待っている間も一時停止やシークにはすぐに反応する
+    pub fn tick(&self, seen_generation: &mut u64, wait: Option<Duration>) -> Option<NaiveDate> {
+        let mut state = self.state.lock().unwrap();
+        let deadline = Instant::now() + wait.unwrap_or_else(|| Duration::from_millis(INTERVAL_MILLS).div_f64(state.speed));
+        loop {
+            if state.seek_generation != *seen_generation {
+                *seen_generation = state.seek_generation;
+                return state.seek_to;
+            }
+            let now = Instant::now();
+            if state.paused {
+                state = self.changed.wait(state).unwrap();
+            } else if now < deadline {
+                state = self.changed.wait_timeout(state, deadline - now).unwrap().0;
+            } else {
+                return None;
+            }
+        }
+    }
+
+    fn execute(&self, command: &str) -> anyhow::Result<String> {
+        let mut state = self.state.lock().unwrap();
+        let mut words = command.split_whitespace();
+        let name = words.next().unwrap_or("");
+        let mut value = || words.next().ok_or_else(|| anyhow::anyhow!("missing argument for {name}"));
+        match name {
+            "pause" => state.paused = true,
+            "resume" => state.paused = false,
+            "seek" => {
+                state.seek_to = Some(value()?.parse()?);
+                state.seek_generation += 1;
+            }
+            "speed" => {
+                let speed: f64 = value()?.parse()?;
+                anyhow::ensure!(speed > 0.0, "speed must be positive");
+                state.speed = speed;
+            }
+            "status" => {}
+            other => anyhow::bail!("unknown command: {other}"),
+        }
+        self.changed.notify_all();
+        Ok(format!("{} speed={}", if state.paused { "paused" } else { "playing" }, state.speed))
+    }
+
+    fn serve(&self, stream: TcpStream) -> anyhow::Result<()> {
+        let mut writer = stream.try_clone()?;
+        for line in BufReader::new(stream).lines() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            match self.execute(&line) {
+                Ok(status) => writeln!(writer, "ok {status}")?,
+                Err(err) => writeln!(writer, "error {err}")?,
+            }
+        }
+        Ok(())
+    }
+
+    // 制御用ポートで待ち受ける
+    pub fn listen(self: Arc<Self>, addr: SocketAddr) -> anyhow::Result<()> {
+        let listener = TcpListener::bind(addr)?;
+        println!("Control listening on {}", listener.local_addr()?);
+        thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let control = self.clone();
+                thread::spawn(move || control.serve(stream));
+            }
+        });
+        Ok(())
+    }
+}
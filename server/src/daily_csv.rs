@@ -0,0 +1,181 @@
+use crate::element::{ELEMENTS, ELEMENT_COUNT};
+use crate::quality::{Quality, QualityInfo};
+use crate::ObservationPointData;
+use chrono::NaiveDate;
+use csv::StringRecord;
+
+const DATE_COLUMN: &str = "年月日";
+// 値の列の直後に続く付帯情報の列
+const QUALITY_COLUMN: &str = "品質情報";
+const HOMOGENEITY_COLUMN: &str = "均質番号";
+const NO_PHENOMENON_COLUMN: &str = "現象なし情報";
+
+#[derive(Clone, Copy, Debug)]
+struct Column {
+    value: usize,
+    quality: Option<usize>,
+    homogeneity: Option<usize>,
+    no_phenomenon: Option<usize>,
+}
+
+impl Column {
+    fn parse<'a>(&self, row: &'a StringRecord) -> (&'a str, QualityInfo) {
+        let code = |i: Option<usize>| i.and_then(|i| row.get(i)).and_then(|x| x.trim().parse::<u8>().ok());
+        let info = QualityInfo {
+            quality: code(self.quality).and_then(Quality::from_code),
+            homogeneity: code(self.homogeneity).filter(|&x| x != 0),
+        };
+        let value = row.get(self.value).unwrap_or("");
+        // 降水・積雪の「現象なし」は 0 として扱う
+        if value.trim().is_empty() && code(self.no_phenomenon) == Some(1) {
+            return ("0", info);
+        }
+        (value, info)
+    }
+}
+
+// 日別値 CSV の列の並び
+// 前処理済みの「年月日,平均,最高,最低」だけの CSV と、
+// 品質情報・均質番号の列が残った気象庁のダウンロード形式（UTF-8 に変換したもの）のどちらも読める
+#[derive(Clone, Debug)]
+pub struct DailyCsvLayout {
+    date: usize,
+    // Element の順
+    columns: [Option<Column>; ELEMENT_COUNT],
+}
+
+impl DailyCsvLayout {
+    pub fn from_headers(headers: &StringRecord) -> anyhow::Result<Self> {
+        let mut date = None;
+        let mut columns: Vec<(&str, Column)> = Vec::new();
+        for (i, header) in headers.iter().enumerate() {
+            let header = header.trim();
+            if header == DATE_COLUMN {
+                date = Some(i);
+            } else if header.contains(QUALITY_COLUMN) {
+                if let Some((_, column)) = columns.last_mut() {
+                    column.quality = Some(i);
+                }
+            } else if header.contains(HOMOGENEITY_COLUMN) {
+                if let Some((_, column)) = columns.last_mut() {
+                    column.homogeneity = Some(i);
+                }
+            } else if header.contains(NO_PHENOMENON_COLUMN) {
+                if let Some((_, column)) = columns.last_mut() {
+                    column.no_phenomenon = Some(i);
+                }
+            } else {
+                columns.push((header, Column { value: i, quality: None, homogeneity: None, no_phenomenon: None }));
+            }
+        }
+        let find = |name: &str| columns.iter().find(|(header, _)| *header == name).map(|(_, column)| *column);
+
+        Ok(Self {
+            date: date.ok_or_else(|| anyhow::anyhow!("missing column: {DATE_COLUMN}"))?,
+            columns: ELEMENTS.map(|x| find(x.csv_column())),
+        })
+    }
+
+    // 見出しの行（データの行より前の行すべて）から列の並びを読む
+    // 気象庁のダウンロード形式は、ダウンロードした時刻・地点名の行の後に要素名の行があり、
+    // 要素名が値・品質情報・均質番号の列に繰り返され、その下の行に品質情報などの見出しが付く
+    // 要素名の行の下にある見出しを優先して、1 行の見出しにまとめる
+    pub fn from_header_rows(rows: &[StringRecord]) -> anyhow::Result<Self> {
+        let start = rows.iter()
+            .position(|row| row.iter().any(|x| x.trim() == DATE_COLUMN))
+            .ok_or_else(|| anyhow::anyhow!("missing column: {DATE_COLUMN}"))?;
+        let headers = rows[start].iter().enumerate()
+            .map(|(i, header)| rows[start + 1..].iter()
+                .filter_map(|row| row.get(i).map(str::trim))
+                .find(|x| !x.is_empty())
+                .unwrap_or(header))
+            .collect::<StringRecord>();
+        Self::from_headers(&headers)
+    }
+
+    // 先頭の列が日付ならデータの行
+    pub fn is_data_row(row: &StringRecord) -> bool {
+        row.get(0).is_some_and(|x| NaiveDate::parse_from_str(x.trim(), "%Y/%m/%d").is_ok())
+    }
+
+    pub fn parse(&self, id: u32, row: &StringRecord) -> anyhow::Result<(NaiveDate, ObservationPointData)> {
+        let date = NaiveDate::parse_from_str(row.get(self.date).unwrap_or(""), "%Y/%m/%d")?;
+        let mut data = ObservationPointData::new(id);
+        for (element, column) in ELEMENTS.iter().zip(&self.columns) {
+            if let Some(column) = column {
+                let (value, quality) = column.parse(row);
+                data.set(*element, value, quality);
+            }
+        }
+        Ok((date, data))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::element::Element;
+    use crate::station_reader::{CsvSource, StationReader};
+    use std::path::Path;
+
+    fn read(path: &Path) -> Vec<(NaiveDate, ObservationPointData)> {
+        let reader = StationReader::open(44132, vec![path.to_path_buf()]).unwrap();
+        CsvSource::new(vec![reader])
+            .map(|x| {
+                let (date, mut rows) = x.unwrap();
+                (date, rows.pop().unwrap())
+            })
+            .collect()
+    }
+
+    fn info(quality: Quality, homogeneity: u8) -> QualityInfo {
+        QualityInfo { quality: Some(quality), homogeneity: Some(homogeneity) }
+    }
+
+    #[test]
+    fn reads_jma_download_format() {
+        let rows = read(&Path::new(env!("CARGO_MANIFEST_DIR")).join("testdata/jma_daily.csv"));
+        let dates = rows.iter().map(|(date, _)| date.to_string()).collect::<Vec<_>>();
+        assert_eq!(dates, ["2024-07-01", "2024-07-02", "2024-07-03"]);
+
+        let (_, day) = &rows[0];
+        assert_eq!(day.point_id(), 44132);
+        assert_eq!([day.average(), day.max(), day.min()], [Some(24.6), Some(28.0), Some(21.9)]);
+        assert_eq!(day.get(Element::Precipitation), Some(12.5));
+        assert_eq!(day.quality(Element::AverageTemperature), info(Quality::Normal, 1));
+        assert_eq!(day.quality(Element::Precipitation), info(Quality::Normal, 1));
+
+        let (_, day) = &rows[1];
+        assert_eq!(day.quality(Element::AverageTemperature), info(Quality::QuasiNormal, 1));
+        assert_eq!(day.quality(Element::MinTemperature), info(Quality::Insufficient, 1));
+        // 現象なしの降水量は 0
+        assert_eq!(day.get(Element::Precipitation), Some(0.0));
+
+        let (_, day) = &rows[2];
+        assert_eq!([day.average(), day.max(), day.min()], [None, Some(33.1), None]);
+        assert_eq!(day.quality(Element::AverageTemperature), info(Quality::Missing, 1));
+        assert_eq!(day.quality(Element::MinTemperature), info(Quality::NotObserved, 1));
+        assert_eq!(day.quality(Element::Precipitation), info(Quality::Suspect, 1));
+        // 列のない要素は欠測
+        assert_eq!(day.get(Element::Sunshine), None);
+    }
+
+    #[test]
+    fn reads_preprocessed_format() {
+        let headers = [StringRecord::from(vec!["年月日", "平均気温(℃)", "最高気温(℃)", "最低気温(℃)"])];
+        let layout = DailyCsvLayout::from_header_rows(&headers).unwrap();
+        let row = StringRecord::from(vec!["1970/1/2", "3.1", "8.0)", ""]);
+        assert!(DailyCsvLayout::is_data_row(&row));
+        let (date, day) = layout.parse(40046, &row).unwrap();
+        assert_eq!(date, NaiveDate::from_ymd_opt(1970, 1, 2).unwrap());
+        assert_eq!([day.average(), day.max(), day.min()], [Some(3.1), Some(8.0), None]);
+        assert_eq!(day.quality(Element::MaxTemperature).quality, Some(Quality::QuasiNormal));
+        assert!(!day.quality(Element::AverageTemperature).is_known());
+    }
+
+    #[test]
+    fn requires_date_column() {
+        let headers = [StringRecord::from(vec!["ダウンロードした時刻：2024/08/01 10:15:23"])];
+        assert!(DailyCsvLayout::from_header_rows(&headers).is_err());
+    }
+}
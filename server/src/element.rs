@@ -0,0 +1,147 @@
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::str::FromStr;
+
+// 日別値の観測要素
+// 値はすべて 0.1 単位の整数で持つ（湿度 0.1 %, 積雪 0.1 cm なども同様）
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[repr(u8)]
+pub enum Element {
+    AverageTemperature = 0,
+    MaxTemperature = 1,
+    MinTemperature = 2,
+    // 降水量の合計 (mm)
+    Precipitation = 3,
+    // 日照時間 (時間)
+    Sunshine = 4,
+    // 平均風速 (m/s)
+    AverageWindSpeed = 5,
+    // 最大瞬間風速 (m/s)
+    MaxGust = 6,
+    // 平均湿度 (%)
+    Humidity = 7,
+    // 最深積雪 (cm)
+    SnowDepth = 8,
+}
+
+pub const ELEMENT_COUNT: usize = 9;
+
+pub const ELEMENTS: [Element; ELEMENT_COUNT] = [
+    Element::AverageTemperature,
+    Element::MaxTemperature,
+    Element::MinTemperature,
+    Element::Precipitation,
+    Element::Sunshine,
+    Element::AverageWindSpeed,
+    Element::MaxGust,
+    Element::Humidity,
+    Element::SnowDepth,
+];
+
+impl Element {
+    pub fn index(self) -> usize {
+        self as usize
+    }
+
+    pub fn is_temperature(self) -> bool {
+        matches!(self, Element::AverageTemperature | Element::MaxTemperature | Element::MinTemperature)
+    }
+
+    // 気象庁 CSV の列名
+    pub fn csv_column(self) -> &'static str {
+        match self {
+            Element::AverageTemperature => "平均気温(℃)",
+            Element::MaxTemperature => "最高気温(℃)",
+            Element::MinTemperature => "最低気温(℃)",
+            Element::Precipitation => "降水量の合計(mm)",
+            Element::Sunshine => "日照時間(時間)",
+            Element::AverageWindSpeed => "平均風速(m/s)",
+            Element::MaxGust => "最大瞬間風速(m/s)",
+            Element::Humidity => "平均湿度(％)",
+            Element::SnowDepth => "最深積雪(cm)",
+        }
+    }
+
+    // シリアライズ時のキー
+    pub fn name(self) -> &'static str {
+        match self {
+            Element::AverageTemperature => "averageTemperature",
+            Element::MaxTemperature => "maxTemperature",
+            Element::MinTemperature => "minTemperature",
+            Element::Precipitation => "precipitation",
+            Element::Sunshine => "sunshine",
+            Element::AverageWindSpeed => "averageWindSpeed",
+            Element::MaxGust => "maxGust",
+            Element::Humidity => "humidity",
+            Element::SnowDepth => "snowDepth",
+        }
+    }
+}
+
+impl FromStr for Element {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        ELEMENTS.into_iter()
+            .find(|x| x.name() == s)
+            .ok_or_else(|| anyhow::anyhow!("unknown element: {s}"))
+    }
+}
+
+// name() の文字列として読み書きする
+impl Serialize for Element {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.name())
+    }
+}
+
+impl<'de> Deserialize<'de> for Element {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+// 要素の集合（ビット i が Element の i 番目に対応）
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ElementSet(u16);
+
+impl ElementSet {
+    pub const TEMPERATURE: ElementSet = ElementSet(0b111);
+
+    pub fn from_bits(bits: u16) -> Self {
+        Self(bits & ((1 << ELEMENT_COUNT) - 1))
+    }
+
+    pub fn bits(self) -> u16 {
+        self.0
+    }
+
+    pub fn contains(self, element: Element) -> bool {
+        self.0 & (1 << element.index()) != 0
+    }
+
+    pub fn insert(&mut self, element: Element) {
+        self.0 |= 1 << element.index();
+    }
+
+    pub fn union(self, other: ElementSet) -> ElementSet {
+        Self(self.0 | other.0)
+    }
+
+    pub fn intersects(self, other: ElementSet) -> bool {
+        self.0 & other.0 != 0
+    }
+
+    pub fn iter(self) -> impl Iterator<Item = Element> {
+        ELEMENTS.into_iter().filter(move |&x| self.contains(x))
+    }
+}
+
+impl FromIterator<Element> for ElementSet {
+    fn from_iter<T: IntoIterator<Item = Element>>(iter: T) -> Self {
+        let mut res = Self::default();
+        for element in iter {
+            res.insert(element);
+        }
+        res
+    }
+}
@@ -0,0 +1,96 @@
+use crate::rng::Rng;
+use std::time::{Duration, Instant};
+
+// 障害を起こす確率（すべて 0 なら何もしない）
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Faults {
+    pub seed: u64,
+    // フレームを送らない
+    pub drop: f64,
+    // 同じフレームを 2 回送る
+    pub duplicate: f64,
+    // フレームの途中までしか送らない
+    pub truncate: f64,
+    // しばらく送るのを止め、溜めたフレームをまとめて送る
+    pub delay: f64,
+    pub delay_duration: Duration,
+    // 接続を切る
+    pub disconnect: f64,
+}
+
+impl Default for Faults {
+    fn default() -> Self {
+        Self {
+            seed: 0,
+            drop: 0.0,
+            duplicate: 0.0,
+            truncate: 0.0,
+            delay: 0.0,
+            delay_duration: Duration::from_millis(500),
+            disconnect: 0.0,
+        }
+    }
+}
+
+impl Faults {
+    pub fn parse_probability(s: &str) -> anyhow::Result<f64> {
+        let p: f64 = s.parse()?;
+        anyhow::ensure!((0.0..=1.0).contains(&p), "probability must be between 0 and 1: {s}");
+        Ok(p)
+    }
+}
+
+// 接続ごとに、シードから決まる順番で障害を起こす
+pub(crate) struct FaultInjector {
+    faults: Faults,
+    rng: Rng,
+    // 遅延中に溜めているデータと、それを送る時刻
+    held: Vec<u8>,
+    release_at: Option<Instant>,
+}
+
+impl FaultInjector {
+    pub fn new(faults: Faults) -> Self {
+        Self { faults, rng: Rng::new(faults.seed), held: Vec::new(), release_at: None }
+    }
+
+    pub fn send(&mut self, frame: &[u8], mut write: impl FnMut(&[u8]) -> anyhow::Result<()>) -> anyhow::Result<()> {
+        let faults = self.faults;
+        if self.rng.chance(faults.disconnect) {
+            println!("Fault: disconnect");
+            anyhow::bail!("disconnected by fault injection");
+        }
+        if self.rng.chance(faults.drop) {
+            return Ok(());
+        }
+        let mut data = frame.to_vec();
+        if self.rng.chance(faults.truncate) {
+            data.truncate(self.rng.below(frame.len()));
+        }
+        if self.rng.chance(faults.duplicate) {
+            data.extend_from_slice(frame);
+        }
+        if self.release_at.is_none() && self.rng.chance(faults.delay) {
+            self.release_at = Some(Instant::now() + faults.delay_duration);
+        }
+
+        self.held.extend_from_slice(&data);
+        if self.release_at.is_some_and(|x| Instant::now() < x) {
+            return Ok(());
+        }
+        self.release_at = None;
+        write(&self.held)?;
+        self.held.clear();
+        Ok(())
+    }
+
+    // 遅延中に溜めているデータを待たずに送る（再生が終わったときに呼ぶ）
+    pub fn flush(&mut self, mut write: impl FnMut(&[u8]) -> anyhow::Result<()>) -> anyhow::Result<()> {
+        self.release_at = None;
+        let held = std::mem::take(&mut self.held);
+        if !held.is_empty() {
+            write(&held)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,525 @@
+use crate::crc32::crc32;
+use crate::element::{Element, ElementSet};
+use crate::ObservationPointData;
+use chrono::NaiveDate;
+use std::fmt;
+
+// フレーム先頭のマジックナンバー
+// 旧形式の先頭 2 バイト（件数, 日数の上位バイト）と一致するのは 218 地点かつ 2055 年以降のみ
+pub const MAGIC: [u8; 2] = [0xDA, 0x7A];
+// magic(2) + version(1) + record type(1) + flags(2) + payload length(4)
+pub const HEADER_LEN: usize = 10;
+
+// ヘッダなしの旧形式 `[len u8][days u16][6-byte records]`
+pub const VERSION_LEGACY: u8 = 0;
+pub const VERSION_1: u8 = 1;
+// 件数・地点番号を varint、日付を 1970/1/1 からの符号付き日数 (zigzag varint) で表す
+pub const VERSION_2: u8 = 2;
+// v2 の気温 (10 bit x 3, -51.2 ℃..=51.1 ℃) を i16 x 3 にしたもの
+pub const VERSION_3: u8 = 3;
+// 送信時に使うバージョン
+pub const CURRENT_VERSION: u8 = VERSION_3;
+
+// これを超える長さのペイロードは壊れたフレームとみなす
+pub const MAX_PAYLOAD_LEN: usize = 1 << 20;
+
+// ヘッダの flags
+// v2 以降: 各レコードに要素ごとの値の有無を表すビット (varint) が付く
+pub const FLAG_PRESENCE: u16 = 0x0001;
+// v2 以降: 各レコードに要素ごとの品質情報と均質番号が付く
+pub const FLAG_QUALITY: u16 = 0x0002;
+// v2 以降: ペイロードに要素の集合 (varint) が付く。ない場合は平均・最高・最低気温
+pub const FLAG_ELEMENTS: u16 = 0x0004;
+// ペイロードの後ろにヘッダとペイロードの CRC-32 (u32) が付く
+pub const FLAG_CRC32: u16 = 0x0008;
+
+pub const CRC_LEN: usize = 4;
+
+const LEGACY_HEADER_LEN: usize = 3;
+const RECORD_LEN: usize = 6;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DecodeError {
+    TruncatedHeader,
+    TruncatedPayload { expected: usize, actual: usize },
+    // index 番目のレコードが途中で切れている
+    TruncatedRecord { index: usize },
+    // 1970/1/1 からの日数が日付として表せない
+    BadDate(i64),
+    UnknownStation(u32),
+    UnsupportedFrame { version: u8, record_type: u8 },
+    ChecksumMismatch { expected: u32, actual: u32 },
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeError::TruncatedHeader => write!(f, "truncated frame header"),
+            DecodeError::TruncatedPayload { expected, actual } =>
+                write!(f, "truncated payload: expected {expected} bytes, got {actual}"),
+            DecodeError::TruncatedRecord { index } => write!(f, "truncated record at index {index}"),
+            DecodeError::BadDate(days) => write!(f, "bad date: {days} days since 1970-01-01"),
+            DecodeError::UnknownStation(id) => write!(f, "unknown station id: {id}"),
+            DecodeError::UnsupportedFrame { version, record_type } =>
+                write!(f, "unsupported frame: version {version}, record type {record_type}"),
+            DecodeError::ChecksumMismatch { expected, actual } =>
+                write!(f, "checksum mismatch: expected {expected:08x}, got {actual:08x}"),
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EncodeError {
+    // 旧形式では 1 フレーム 255 地点まで
+    TooManyRecords(usize),
+    // 旧形式では 1970/1/1 から 65535 日まで
+    DateOutOfRange(NaiveDate),
+    // 旧形式では 40000..=105535 まで
+    StationIdOutOfRange(u32),
+    // 値は 0.1 ℃単位
+    TemperatureOutOfRange { id: u32, element: Element, value: i32 },
+    UnsupportedVersion(u8),
+}
+
+impl fmt::Display for EncodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EncodeError::TooManyRecords(len) => write!(f, "too many records for this frame version: {len}"),
+            EncodeError::DateOutOfRange(date) => write!(f, "date out of range for this frame version: {date}"),
+            EncodeError::StationIdOutOfRange(id) => write!(f, "station id out of range for this frame version: {id}"),
+            EncodeError::TemperatureOutOfRange { id, element, value } => write!(
+                f, "temperature out of range for this frame version: {} = {:.1} at station {id}",
+                element.name(), *value as f64 / 10.0,
+            ),
+            EncodeError::UnsupportedVersion(version) => write!(f, "unsupported frame version: {version}"),
+        }
+    }
+}
+
+impl std::error::Error for EncodeError {}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum RecordType {
+    // 日ごとの平均・最高・最低気温
+    DailyTemperature = 1,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FrameHeader {
+    pub version: u8,
+    pub record_type: u8,
+    pub flags: u16,
+    pub payload_len: u32,
+}
+
+impl FrameHeader {
+    pub fn encode(&self) -> [u8; HEADER_LEN] {
+        let mut res = [0u8; HEADER_LEN];
+        res[0..2].copy_from_slice(&MAGIC);
+        res[2] = self.version;
+        res[3] = self.record_type;
+        res[4..6].copy_from_slice(&self.flags.to_be_bytes());
+        res[6..10].copy_from_slice(&self.payload_len.to_be_bytes());
+        res
+    }
+
+    // CRC を含むフレーム全体の長さ
+    pub fn frame_len(&self) -> usize {
+        let crc_len = if self.flags & FLAG_CRC32 != 0 { CRC_LEN } else { 0 };
+        HEADER_LEN + self.payload_len as usize + crc_len
+    }
+
+    pub fn decode(data: &[u8]) -> Option<Self> {
+        if data.len() < HEADER_LEN || data[0..2] != MAGIC {
+            return None;
+        }
+        Some(Self {
+            version: data[2],
+            record_type: data[3],
+            flags: u16::from_be_bytes([data[4], data[5]]),
+            payload_len: u32::from_be_bytes([data[6], data[7], data[8], data[9]]),
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FrameLength {
+    // フレーム全体の長さが確定した
+    Known(usize),
+    // 長さを決めるには先頭からこのバイト数が必要
+    NeedMore(usize),
+}
+
+// ストリームから読んだ先頭部分を見て、フレーム全体の長さを求める
+pub fn frame_length(prefix: &[u8]) -> FrameLength {
+    if prefix.len() < LEGACY_HEADER_LEN {
+        return FrameLength::NeedMore(LEGACY_HEADER_LEN);
+    }
+    if prefix[0..2] != MAGIC {
+        return FrameLength::Known(LEGACY_HEADER_LEN + RECORD_LEN * prefix[0] as usize);
+    }
+    match FrameHeader::decode(prefix) {
+        Some(header) => FrameLength::Known(header.frame_len()),
+        None => FrameLength::NeedMore(HEADER_LEN),
+    }
+}
+
+fn epoch() -> NaiveDate {
+    NaiveDate::from_ymd_opt(1970, 1, 1).unwrap()
+}
+
+pub(crate) fn write_varint(out: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        out.push((value as u8 & 0x7F) | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+pub(crate) fn read_varint(data: &mut &[u8]) -> Option<u64> {
+    let mut res = 0u64;
+    for shift in (0..64).step_by(7) {
+        let byte = *data.split_off_first()?;
+        res |= ((byte & 0x7F) as u64) << shift;
+        if byte & 0x80 == 0 {
+            return Some(res);
+        }
+    }
+    None
+}
+
+pub(crate) fn zigzag(value: i64) -> u64 {
+    ((value << 1) ^ (value >> 63)) as u64
+}
+
+pub(crate) fn unzigzag(value: u64) -> i64 {
+    (value >> 1) as i64 ^ -((value & 1) as i64)
+}
+
+// 旧形式は欠測を表せないため、欠けたフィールドのあるレコードは送らない
+fn encode_payload_v1(date: NaiveDate, data: &[ObservationPointData]) -> Result<Vec<u8>, EncodeError> {
+    let data = data.iter().filter(|x| x.has_all_temperatures()).collect::<Vec<_>>();
+    let len = u8::try_from(data.len()).map_err(|_| EncodeError::TooManyRecords(data.len()))?;
+    let days = u16::try_from((date - epoch()).num_days()).map_err(|_| EncodeError::DateOutOfRange(date))?;
+    let mut res = Vec::with_capacity(LEGACY_HEADER_LEN + RECORD_LEN * data.len());
+    res.push(len);
+    res.extend_from_slice(&days.to_be_bytes());
+
+    for row in data {
+        res.extend_from_slice(&row.compress()?);
+    }
+
+    Ok(res)
+}
+
+fn encode_payload_v2(version: u8, date: NaiveDate, data: &[ObservationPointData], flags: u16, elements: ElementSet) -> Result<Vec<u8>, EncodeError> {
+    let mut res = Vec::new();
+    write_varint(&mut res, zigzag((date - epoch()).num_days()));
+    write_varint(&mut res, data.len() as u64);
+    if flags & FLAG_ELEMENTS != 0 {
+        write_varint(&mut res, elements.bits() as u64);
+    }
+
+    for row in data {
+        row.compress_v2(&mut res, version, flags, elements)?;
+    }
+
+    Ok(res)
+}
+
+fn decode_date(days: i64) -> Result<NaiveDate, DecodeError> {
+    chrono::Duration::try_days(days)
+        .and_then(|d| epoch().checked_add_signed(d))
+        .ok_or(DecodeError::BadDate(days))
+}
+
+fn decode_payload_v1(mut data: &[u8]) -> Result<(NaiveDate, Vec<ObservationPointData>), DecodeError> {
+    let len = *data.split_off_first().ok_or(DecodeError::TruncatedHeader)? as usize;
+    let days = data.split_off(..2).ok_or(DecodeError::TruncatedHeader)?;
+    let days = u16::from_be_bytes([days[0], days[1]]);
+    let mut res = Vec::with_capacity(len);
+    for index in 0..len {
+        let (record, rest) = data.split_first_chunk::<RECORD_LEN>().ok_or(DecodeError::TruncatedRecord { index })?;
+        res.push(ObservationPointData::decompress(record));
+        data = rest;
+    }
+
+    Ok((decode_date(days as i64)?, res))
+}
+
+fn decode_payload_v2(version: u8, mut data: &[u8], flags: u16) -> Result<(NaiveDate, Vec<ObservationPointData>), DecodeError> {
+    let days = read_varint(&mut data).ok_or(DecodeError::TruncatedHeader)?;
+    let date = decode_date(unzigzag(days))?;
+    let len = read_varint(&mut data).ok_or(DecodeError::TruncatedHeader)? as usize;
+    let elements = if flags & FLAG_ELEMENTS != 0 {
+        let bits = read_varint(&mut data).ok_or(DecodeError::TruncatedHeader)?;
+        ElementSet::from_bits(bits as u16)
+    } else {
+        ElementSet::TEMPERATURE
+    };
+    // 件数は信用せず、確保量は残りバイト数で抑える
+    let mut res = Vec::with_capacity(len.min(data.len()));
+    for index in 0..len {
+        let record = ObservationPointData::decompress_v2(&mut data, version, flags, elements);
+        res.push(record.ok_or(DecodeError::TruncatedRecord { index })?);
+    }
+
+    Ok((date, res))
+}
+
+// version のフレームで送れない値を取り除き、取り除いた理由を返す
+// 範囲外の気温はその要素だけを欠測にし、旧形式・v1 で範囲外の地点番号はレコードごと落とす
+pub fn drop_out_of_range(version: u8, data: &mut Vec<ObservationPointData>) -> Vec<EncodeError> {
+    let mut res = Vec::new();
+    data.retain_mut(|row| {
+        res.extend(row.clear_out_of_range(version));
+        // 旧形式・v1 では欠けたフィールドのあるレコードはもともと送らない
+        if version > VERSION_1 || !row.has_all_temperatures() {
+            return true;
+        }
+        match row.legacy_id() {
+            Ok(_) => true,
+            Err(err) => {
+                res.push(err);
+                false
+            }
+        }
+    });
+    res
+}
+
+// 指定したバージョンでフレームを組み立てる
+pub fn encode_frame(version: u8, date: NaiveDate, data: &[ObservationPointData]) -> Result<Vec<u8>, EncodeError> {
+    // 気温以外の要素・欠測・品質情報を含むときだけ対応するフィールドを付ける
+    let elements = data.iter().fold(ElementSet::default(), |acc, x| acc.union(x.elements()));
+    let mut flags = 0;
+    if elements != ElementSet::TEMPERATURE {
+        flags |= FLAG_ELEMENTS;
+    }
+    if data.iter().any(|x| x.elements() != elements) {
+        flags |= FLAG_PRESENCE;
+    }
+    if data.iter().any(|x| x.has_quality()) {
+        flags |= FLAG_QUALITY;
+    }
+    let (payload, flags) = match version {
+        VERSION_LEGACY => return encode_payload_v1(date, data),
+        VERSION_1 => (encode_payload_v1(date, data)?, 0),
+        VERSION_2 | VERSION_3 => (encode_payload_v2(version, date, data, flags, elements)?, flags),
+        _ => return Err(EncodeError::UnsupportedVersion(version)),
+    };
+    let header = FrameHeader {
+        version,
+        record_type: RecordType::DailyTemperature as u8,
+        flags: flags | FLAG_CRC32,
+        payload_len: payload.len() as u32,
+    };
+    let mut res = Vec::with_capacity(header.frame_len());
+    res.extend_from_slice(&header.encode());
+    res.extend_from_slice(&payload);
+    res.extend_from_slice(&crc32(&res).to_be_bytes());
+    Ok(res)
+}
+
+pub fn compress_data(date: NaiveDate, data: &[ObservationPointData]) -> Result<Vec<u8>, EncodeError> {
+    encode_frame(CURRENT_VERSION, date, data)
+}
+
+fn verify_checksum(frame: &[u8], crc: &[u8]) -> Result<(), DecodeError> {
+    let (crc, _) = crc.split_first_chunk::<CRC_LEN>().ok_or(DecodeError::TruncatedPayload {
+        expected: frame.len() - HEADER_LEN + CRC_LEN,
+        actual: frame.len() - HEADER_LEN + crc.len(),
+    })?;
+    let expected = u32::from_be_bytes(*crc);
+    let actual = crc32(frame);
+    if expected != actual {
+        return Err(DecodeError::ChecksumMismatch { expected, actual });
+    }
+    Ok(())
+}
+
+// フレームが壊れていないかを確かめる（CRC のないフレームは常に Ok）
+pub fn verify_frame(data: &[u8]) -> Result<(), DecodeError> {
+    let Some(header) = FrameHeader::decode(data) else {
+        return Ok(());
+    };
+    if header.flags & FLAG_CRC32 == 0 {
+        return Ok(());
+    }
+    let end = HEADER_LEN + header.payload_len as usize;
+    let frame = data.get(..end).ok_or(DecodeError::TruncatedPayload {
+        expected: header.payload_len as usize,
+        actual: data.len() - HEADER_LEN,
+    })?;
+    verify_checksum(frame, &data[end..])
+}
+
+// ヘッダ付き・旧形式のどちらのフレームも受け付ける
+pub fn decompress_data(data: &[u8]) -> Result<(NaiveDate, Vec<ObservationPointData>), DecodeError> {
+    if !data.starts_with(&MAGIC) {
+        return decode_payload_v1(data);
+    }
+    let header = FrameHeader::decode(data).ok_or(DecodeError::TruncatedHeader)?;
+    let expected = header.payload_len as usize;
+    let payload = data.get(HEADER_LEN..HEADER_LEN + expected)
+        .ok_or(DecodeError::TruncatedPayload { expected, actual: data.len() - HEADER_LEN })?;
+    if header.flags & FLAG_CRC32 != 0 {
+        verify_checksum(&data[..HEADER_LEN + expected], &data[HEADER_LEN + expected..])?;
+    }
+    match (header.version, header.record_type) {
+        (VERSION_1, t) if t == RecordType::DailyTemperature as u8 => decode_payload_v1(payload),
+        (VERSION_2 | VERSION_3, t) if t == RecordType::DailyTemperature as u8 =>
+            decode_payload_v2(header.version, payload, header.flags),
+        (version, record_type) => Err(DecodeError::UnsupportedFrame { version, record_type }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::element::ELEMENTS;
+    use crate::quality::{Quality, QualityInfo};
+
+    fn date() -> NaiveDate {
+        NaiveDate::from_ymd_opt(2023, 8, 1).unwrap()
+    }
+
+    fn point(id: u32, values: [Option<f64>; 3]) -> ObservationPointData {
+        let mut res = ObservationPointData::new(id);
+        for (element, value) in [Element::AverageTemperature, Element::MaxTemperature, Element::MinTemperature].into_iter().zip(values) {
+            res.set_value(element, value);
+        }
+        res
+    }
+
+    fn assert_same(actual: &[ObservationPointData], expected: &[ObservationPointData]) {
+        assert_eq!(actual.len(), expected.len());
+        for (a, e) in actual.iter().zip(expected) {
+            assert_eq!(a.point_id(), e.point_id());
+            for element in ELEMENTS {
+                assert_eq!(a.get(element), e.get(element), "{} at {}", element.name(), e.point_id());
+                assert_eq!(a.quality(element), e.quality(element), "{} at {}", element.name(), e.point_id());
+            }
+        }
+    }
+
+    #[test]
+    fn round_trip_v1() {
+        let data = [point(47662, [Some(25.3), Some(31.0), Some(-0.4)]),
+            point(40046, [Some(-51.2), Some(51.1), Some(0.0)])];
+        for version in [VERSION_LEGACY, VERSION_1] {
+            let frame = encode_frame(version, date(), &data).unwrap();
+            assert_eq!(frame.starts_with(&MAGIC), version != VERSION_LEGACY);
+            let (decoded_date, decoded) = decompress_data(&frame).unwrap();
+            assert_eq!(decoded_date, date());
+            assert_same(&decoded, &data);
+        }
+    }
+
+    #[test]
+    fn v1_skips_records_with_missing_fields() {
+        let data = [point(47662, [Some(25.3), None, Some(20.1)]), point(47663, [Some(1.0), Some(2.0), Some(3.0)])];
+        let (_, decoded) = decompress_data(&encode_frame(VERSION_1, date(), &data).unwrap()).unwrap();
+        assert_same(&decoded, &data[1..]);
+    }
+
+    #[test]
+    fn round_trip_v2_v3_with_missing_fields() {
+        let data = [point(47662, [Some(25.3), None, Some(20.1)]), point(1, [None, None, None]), point(47663, [Some(1.0), Some(2.0), Some(3.0)])];
+        // v2 以降は 1970 年より前の日付も送れる
+        for version in [VERSION_2, VERSION_3] {
+            for date in [date(), NaiveDate::from_ymd_opt(1934, 7, 9).unwrap()] {
+                let (decoded_date, decoded) = decompress_data(&encode_frame(version, date, &data).unwrap()).unwrap();
+                assert_eq!(decoded_date, date);
+                assert_same(&decoded, &data);
+            }
+        }
+    }
+
+    #[test]
+    fn round_trip_v2_v3_with_quality_and_elements() {
+        let mut a = point(47662, [Some(25.3), Some(31.0), Some(20.1)]);
+        a.set(Element::Precipitation, "12.5)", QualityInfo::default());
+        a.set(Element::Humidity, "75", QualityInfo::default());
+        a.set(Element::SnowDepth, "3", QualityInfo { quality: Some(Quality::Normal), homogeneity: Some(1) });
+        let mut b = point(47663, [Some(-10.0), None, Some(-20.0)]);
+        b.set(Element::Humidity, "×", QualityInfo::default());
+        b.set(Element::Sunshine, "8.2", QualityInfo::default());
+        let data = [a, b];
+        for version in [VERSION_2, VERSION_3] {
+            let frame = encode_frame(version, date(), &data).unwrap();
+            let header = FrameHeader::decode(&frame).unwrap();
+            assert_ne!(header.flags & FLAG_QUALITY, 0);
+            assert_ne!(header.flags & FLAG_ELEMENTS, 0);
+            assert_ne!(header.flags & FLAG_PRESENCE, 0);
+            let (_, decoded) = decompress_data(&frame).unwrap();
+            assert_same(&decoded, &data);
+        }
+    }
+
+    #[test]
+    fn v3_carries_wide_temperature() {
+        let data = [point(47662, [Some(60.0), Some(-60.0), Some(0.0)])];
+        assert!(matches!(encode_frame(VERSION_2, date(), &data), Err(EncodeError::TemperatureOutOfRange { id: 47662, .. })));
+        let (_, decoded) = decompress_data(&encode_frame(VERSION_3, date(), &data).unwrap()).unwrap();
+        assert_same(&decoded, &data);
+    }
+
+    #[test]
+    fn v1_rejects_out_of_range() {
+        let data = [point(1, [Some(1.0), Some(2.0), Some(3.0)])];
+        assert_eq!(encode_frame(VERSION_1, date(), &data).unwrap_err(), EncodeError::StationIdOutOfRange(1));
+        let old = NaiveDate::from_ymd_opt(1934, 7, 9).unwrap();
+        assert_eq!(encode_frame(VERSION_1, old, &[]).unwrap_err(), EncodeError::DateOutOfRange(old));
+    }
+
+    #[test]
+    fn drops_out_of_range_values() {
+        let data = || vec![point(47662, [Some(60.0), Some(70.0), Some(20.1)]), point(1, [Some(1.0), Some(2.0), Some(3.0)])];
+        let mut v1 = data();
+        let errors = drop_out_of_range(VERSION_1, &mut v1);
+        assert_eq!(errors.len(), 3);
+        assert_eq!(errors[2], EncodeError::StationIdOutOfRange(1));
+        assert_eq!(v1.len(), 1);
+        assert_eq!(v1[0].average(), None);
+        assert_eq!(v1[0].min(), Some(20.1));
+        assert!(encode_frame(VERSION_1, date(), &v1).is_ok());
+
+        let mut v2 = data();
+        assert_eq!(drop_out_of_range(VERSION_2, &mut v2).len(), 2);
+        assert_eq!(v2.len(), 2);
+        let (_, decoded) = decompress_data(&encode_frame(VERSION_2, date(), &v2).unwrap()).unwrap();
+        assert_same(&decoded, &v2);
+
+        let mut v3 = data();
+        assert!(drop_out_of_range(VERSION_3, &mut v3).is_empty());
+        assert_same(&v3, &data());
+    }
+
+    #[test]
+    fn detects_checksum_mismatch() {
+        let data = [point(47662, [Some(25.3), Some(31.0), Some(20.1)])];
+        for version in [VERSION_1, VERSION_2, VERSION_3] {
+            let frame = encode_frame(version, date(), &data).unwrap();
+            assert_eq!(verify_frame(&frame), Ok(()));
+            for i in [HEADER_LEN, frame.len() - 1] {
+                let mut broken = frame.clone();
+                broken[i] ^= 0x10;
+                assert!(matches!(verify_frame(&broken), Err(DecodeError::ChecksumMismatch { .. })));
+                assert!(matches!(decompress_data(&broken), Err(DecodeError::ChecksumMismatch { .. })));
+            }
+        }
+    }
+
+    #[test]
+    fn detects_truncated_frame() {
+        let data = [point(47662, [Some(25.3), Some(31.0), Some(20.1)])];
+        let frame = encode_frame(VERSION_3, date(), &data).unwrap();
+        assert!(matches!(decompress_data(&frame[..HEADER_LEN - 1]), Err(DecodeError::TruncatedHeader)));
+        assert!(matches!(decompress_data(&frame[..HEADER_LEN + 1]), Err(DecodeError::TruncatedPayload { .. })));
+        assert!(matches!(decompress_data(&frame[..frame.len() - 1]), Err(DecodeError::TruncatedPayload { .. })));
+    }
+}
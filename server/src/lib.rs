@@ -1,6 +1,16 @@
-use chrono::NaiveDate;
-
+mod crc32;
+pub mod daily_csv;
+pub mod element;
+pub mod frame;
 pub mod observation_points;
+pub mod quality;
+pub mod scanner;
+pub mod station_reader;
+
+pub use frame::{compress_data, decompress_data, DecodeError, EncodeError};
+use frame::{FLAG_PRESENCE, FLAG_QUALITY};
+use element::{Element, ElementSet, ELEMENT_COUNT};
+use quality::{Quality, QualityInfo};
 
 
 // ポート番号
@@ -9,85 +19,226 @@ pub const PORT: u16 = 6051;
 #[derive(Debug)]
 pub struct ObservationPointData {
     id: u32,
-    average: i32,
-    max: i32,
-    min: i32,
+    // Element の順。0.1 単位
+    values: [Option<i32>; ELEMENT_COUNT],
+    quality: [QualityInfo; ELEMENT_COUNT],
 }
 
 impl ObservationPointData {
     pub fn point_id(&self) -> u32 {
         self.id
     }
-    pub fn average(&self) -> f64 {
-        self.average as f64 / 10.0
+    pub fn get(&self, element: Element) -> Option<f64> {
+        self.values[element.index()].map(|x| x as f64 / 10.0)
+    }
+    pub fn quality(&self, element: Element) -> QualityInfo {
+        self.quality[element.index()]
+    }
+    pub fn average(&self) -> Option<f64> {
+        self.get(Element::AverageTemperature)
     }
-    pub fn max(&self) -> f64 {
-        self.max as f64 / 10.0
+    pub fn max(&self) -> Option<f64> {
+        self.get(Element::MaxTemperature)
     }
-    pub fn min(&self) -> f64 {
-        self.min as f64 / 10.0
+    pub fn min(&self) -> Option<f64> {
+        self.get(Element::MinTemperature)
+    }
+    // 値がある要素
+    pub fn elements(&self) -> ElementSet {
+        element::ELEMENTS.into_iter().filter(|x| self.values[x.index()].is_some()).collect()
+    }
+    // すべての要素が欠測
+    pub fn is_empty(&self) -> bool {
+        self.values.iter().all(Option::is_none)
     }
 
-    fn parse_temp(s: &str) -> i32 {
-        let mut res = 0;
-        let mut flag = 1;
-        for c in s.chars() {
-            match c {
-                '-' => { flag = -1; continue; }
-                '.' => { continue; }
-                '0'..='9' => { res = res * 10 + (c as i32 - '0' as i32); }
-                _ => unreachable!(),
-            }
-        }
-        res * flag
+    // 空欄や欠測（×, /// など）は None
+    // 値の後ろに付く品質情報の記号（), ], #）は読み飛ばす
+    fn parse_value(s: &str) -> Option<i32> {
+        let value = s.trim().trim_end_matches([')', ']', '#']).parse::<f64>().ok()?;
+        Some((value * 10.0).round() as i32)
     }
 
-    pub fn new(id: u32, average: &str, max: &str, min: &str) -> Self {
+    pub fn new(id: u32) -> Self {
         Self {
             id,
-            average: Self::parse_temp(average),
-            max: Self::parse_temp(max),
-            min: Self::parse_temp(min),
+            values: [None; ELEMENT_COUNT],
+            quality: [QualityInfo::default(); ELEMENT_COUNT],
         }
     }
 
-    pub fn compress(&self) -> [u8; 6] {
-        let mut res = [0u8; 6];
-        let id = (self.id - 40000) as u16;
-        let ave = (self.average + 512) as u32 & 0x03FF;
-        let max = (self.max + 512) as u32 & 0x03FF;
-        let min = (self.min + 512) as u32 & 0x03FF;
-        let temperature = (max << 20) | (min << 10) | ave;
+    // CSV の値と品質情報の列を設定する（品質情報の列がなければ値の記号から推定する）
+    pub fn set(&mut self, element: Element, value: &str, quality: QualityInfo) {
+        self.values[element.index()] = Self::parse_value(value);
+        self.quality[element.index()] = QualityInfo {
+            quality: quality.quality.or(Quality::from_symbol(value)),
+            homogeneity: quality.homogeneity,
+        };
+    }
 
-        let (r_id, r_temp) = res.split_at_mut(2);
-        r_id.copy_from_slice(&id.to_be_bytes());
-        r_temp.copy_from_slice(&temperature.to_be_bytes());
+    // 数値で値を設定する（単位は Element の説明のとおり、0.1 単位に丸める）
+    pub fn set_value(&mut self, element: Element, value: Option<f64>) {
+        self.values[element.index()] = value.map(|x| (x * 10.0).round() as i32);
+        self.quality[element.index()] = QualityInfo::default();
+    }
 
-        res
+    // 品質が excluded に含まれる要素を欠測扱いにする
+    pub fn exclude_quality(&mut self, excluded: &[Quality]) {
+        for (value, info) in self.values.iter_mut().zip(self.quality) {
+            if info.quality.is_some_and(|x| excluded.contains(&x)) {
+                *value = None;
+            }
+        }
     }
 
-    pub(crate) fn decompress(data: &[u8; 6]) -> Self {
-        let (first, last) = data.split_at(2);
-        let id = u16::from_be_bytes(first.try_into().unwrap()) as u32 + 40000;
+    pub fn has_quality(&self) -> bool {
+        self.quality.iter().any(|x| x.is_known())
+    }
+
+    pub fn has_all_temperatures(&self) -> bool {
+        let elements = self.elements();
+        ElementSet::TEMPERATURE.iter().all(|x| elements.contains(x))
+    }
+
+    const TEMPERATURES: [Element; 3] = [Element::AverageTemperature, Element::MaxTemperature, Element::MinTemperature];
+
+    // 欠測の気温は 0 ℃として詰める
+    // 10 bit に収まらない値は丸めずにエラーにする
+    fn pack_temperature(&self) -> Result<u32, EncodeError> {
+        let mut packed = [0u32; 3];
+        for (res, element) in packed.iter_mut().zip(Self::TEMPERATURES) {
+            let value = self.values[element.index()].unwrap_or(0);
+            if !(-512..512).contains(&value) {
+                return Err(EncodeError::TemperatureOutOfRange { id: self.id, element, value });
+            }
+            *res = (value + 512) as u32;
+        }
+        let [ave, max, min] = packed;
+        Ok((max << 20) | (min << 10) | ave)
+    }
 
-        let temperature = u32::from_be_bytes(last.try_into().unwrap());
+    fn unpack_temperature(&mut self, temperature: u32, present: ElementSet) {
         let max = ((temperature >> 20) & 0x03FF).cast_signed() - 512;
         let min = ((temperature >> 10) & 0x03FF).cast_signed() - 512;
         let average = (temperature & 0x03FF).cast_signed() - 512;
 
-        Self { id, average , max, min}
+        for (element, value) in Self::TEMPERATURES.into_iter().zip([average, max, min]) {
+            self.values[element.index()] = present.contains(element).then_some(value);
+        }
+    }
+
+    // v3: 平均・最高・最低を i16 で並べる
+    fn write_wide_temperature(&self, out: &mut Vec<u8>) -> Result<(), EncodeError> {
+        for element in Self::TEMPERATURES {
+            let value = self.values[element.index()].unwrap_or(0);
+            let value = i16::try_from(value).map_err(|_| EncodeError::TemperatureOutOfRange { id: self.id, element, value })?;
+            out.extend_from_slice(&value.to_be_bytes());
+        }
+        Ok(())
     }
-}
 
-pub fn decompress_data(mut data: &[u8]) -> (NaiveDate, Vec<ObservationPointData>) {
-    let len = *data.split_off_first().unwrap() as usize;
-    let days = data.split_off(..2).unwrap();
-    let days = u16::from_be_bytes(days.try_into().unwrap());
-    let mut res = Vec::with_capacity(len);
-    for _ in 0..len {
-        let tmp = data.split_off(..6).unwrap();
-        res.push(ObservationPointData::decompress(tmp.try_into().unwrap()));
+    fn read_wide_temperature(&mut self, data: &mut &[u8], present: ElementSet) -> Option<()> {
+        for element in Self::TEMPERATURES {
+            let (value, rest) = data.split_first_chunk::<2>()?;
+            *data = rest;
+            self.values[element.index()] = present.contains(element).then_some(i16::from_be_bytes(*value) as i32);
+        }
+        Some(())
     }
 
-    (NaiveDate::from_ymd_opt(1970, 1, 1).unwrap() + chrono::Duration::days(days as i64), res)
+    pub(crate) fn legacy_id(&self) -> Result<u16, EncodeError> {
+        self.id.checked_sub(40000)
+            .and_then(|x| u16::try_from(x).ok())
+            .ok_or(EncodeError::StationIdOutOfRange(self.id))
+    }
+
+    // version のフレームに収まらない気温を欠測にして、その理由を返す
+    pub(crate) fn clear_out_of_range(&mut self, version: u8) -> Vec<EncodeError> {
+        let range = if version >= frame::VERSION_3 { i16::MIN as i32..=i16::MAX as i32 } else { -512..=511 };
+        let mut res = Vec::new();
+        for element in Self::TEMPERATURES {
+            if let Some(value) = self.values[element.index()] && !range.contains(&value) {
+                res.push(EncodeError::TemperatureOutOfRange { id: self.id, element, value });
+                self.values[element.index()] = None;
+            }
+        }
+        res
+    }
+
+    // v1: 地点番号 - 40000 (u16) + 気温 (u32)
+    // 欠測は表せないので、呼び出し側で has_all_temperatures() のレコードだけを渡す
+    pub fn compress(&self) -> Result<[u8; 6], EncodeError> {
+        let mut res = [0u8; 6];
+        let id = self.legacy_id()?;
+
+        let (r_id, r_temp) = res.split_at_mut(2);
+        r_id.copy_from_slice(&id.to_be_bytes());
+        r_temp.copy_from_slice(&self.pack_temperature()?.to_be_bytes());
+
+        Ok(res)
+    }
+
+    pub(crate) fn decompress(data: &[u8; 6]) -> Self {
+        let [i0, i1, t0, t1, t2, t3] = *data;
+        let mut res = Self::new(u16::from_be_bytes([i0, i1]) as u32 + 40000);
+        res.unpack_temperature(u32::from_be_bytes([t0, t1, t2, t3]), ElementSet::TEMPERATURE);
+        res
+    }
+
+    // v2: 地点番号 (varint) + [有無のビット (varint)] + [品質 (2 bytes x 要素数)]
+    //     + [気温 (u32)] + [気温以外の要素 (zigzag varint x 要素数)]
+    // v3: v2 の気温を i16 x 3 にしたもの
+    // 要素は elements に含まれるものだけを Element の順に並べる
+    pub fn compress_v2(&self, out: &mut Vec<u8>, version: u8, flags: u16, elements: ElementSet) -> Result<(), EncodeError> {
+        frame::write_varint(out, self.id as u64);
+        if flags & FLAG_PRESENCE != 0 {
+            frame::write_varint(out, (self.elements().bits() & elements.bits()) as u64);
+        }
+        if flags & FLAG_QUALITY != 0 {
+            for element in elements.iter() {
+                out.extend_from_slice(&self.quality[element.index()].encode());
+            }
+        }
+        if elements.intersects(ElementSet::TEMPERATURE) {
+            if version >= frame::VERSION_3 {
+                self.write_wide_temperature(out)?;
+            } else {
+                out.extend_from_slice(&self.pack_temperature()?.to_be_bytes());
+            }
+        }
+        for element in elements.iter().filter(|x| !x.is_temperature()) {
+            frame::write_varint(out, frame::zigzag(self.values[element.index()].unwrap_or(0) as i64));
+        }
+        Ok(())
+    }
+
+    pub(crate) fn decompress_v2(data: &mut &[u8], version: u8, flags: u16, elements: ElementSet) -> Option<Self> {
+        let mut res = Self::new(u32::try_from(frame::read_varint(data)?).ok()?);
+        let present = if flags & FLAG_PRESENCE != 0 {
+            ElementSet::from_bits(u16::try_from(frame::read_varint(data)?).ok()? & elements.bits())
+        } else {
+            elements
+        };
+        if flags & FLAG_QUALITY != 0 {
+            for element in elements.iter() {
+                let (bytes, rest) = data.split_first_chunk::<2>()?;
+                res.quality[element.index()] = QualityInfo::decode(*bytes);
+                *data = rest;
+            }
+        }
+        if elements.intersects(ElementSet::TEMPERATURE) {
+            if version >= frame::VERSION_3 {
+                res.read_wide_temperature(data, present)?;
+            } else {
+                let (temperature, rest) = data.split_first_chunk::<4>()?;
+                *data = rest;
+                res.unpack_temperature(u32::from_be_bytes(*temperature), present);
+            }
+        }
+        for element in elements.iter().filter(|x| !x.is_temperature()) {
+            let value = i32::try_from(frame::unzigzag(frame::read_varint(data)?)).ok()?;
+            res.values[element.index()] = present.contains(element).then_some(value);
+        }
+        Some(res)
+    }
 }
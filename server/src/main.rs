@@ -1,79 +1,250 @@
-use server::observation_points::{load_observation_points, ObservationPoint};
+mod broadcast;
+mod config;
+mod control;
+mod fault;
+mod pacing;
+mod rng;
+mod synthetic;
+
+use crate::broadcast::Broadcaster;
+use crate::config::{Clock, Config, Source};
+use crate::control::Control;
+use crate::fault::FaultInjector;
+use crate::pacing::Schedule;
+use crate::synthetic::SyntheticSource;
 use chrono::NaiveDate;
-use csv::Reader;
-use server::*;
-use std::io::Write;
-use std::net::{Ipv4Addr, SocketAddrV4, TcpListener, TcpStream};
+use server::observation_points::{load_observation_points, ObservationPoint};
+use server::frame::{drop_out_of_range, encode_frame};
+use server::station_reader::CsvSource;
+use server::EncodeError;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::Arc;
 use std::thread;
 use std::time::Duration;
 
-// 1日ごとの時間間隔（ミリ秒）
-const INTERVAL_MILLS: u64 = 32; // 16
-// 取得する年（10 年単位）
-const DECADES: [u32; 5] = [1980, 1990, 2000, 2010, 2020];
+// 生成データで --start がないときの開始日
+const SYNTHETIC_START: NaiveDate = NaiveDate::from_ymd_opt(2000, 1, 1).unwrap();
 
-fn compress_data(date: NaiveDate, data: &Vec<ObservationPointData>) -> Vec<u8> {
-    let mut res = Vec::new();
-    res.push(data.len() as u8);
-    let days = date - NaiveDate::from_ymd_opt(1970, 1, 1).unwrap();
-    res.extend_from_slice(&(days.num_days() as u16).to_be_bytes());
+enum ReplayEnd {
+    // 最後まで送った
+    Finished { frames: usize },
+    // 途中でシークされた
+    Seek(NaiveDate),
+}
 
-    for row in data {
-        res.extend_from_slice(&row.compress());
-    }
+// フレームを 1 本ずつ作って send に渡す
+// 最初の日付から最後の日付まで 1 本の時系列として送る
+fn replay(
+    send: &mut impl FnMut(Vec<u8>) -> Result<(), anyhow::Error>,
+    observation_points: &[ObservationPoint],
+    config: &Config,
+    control: &Control,
+    seen_generation: &mut u64,
+) -> Result<ReplayEnd, anyhow::Error> {
+    let points = observation_points.iter().filter(|x| config.includes_station(x));
+    let source: Box<dyn Iterator<Item = _>> = match config.source {
+        Source::Csv => Box::new(CsvSource::open(points, &config.data_dir, |x| config.includes_decade(x))?),
+        Source::Synthetic => {
+            let start = config.start.unwrap_or(SYNTHETIC_START);
+            Box::new(SyntheticSource::new(points, config.seed, start, config.end, config.events))
+        }
+    };
 
-    res
+    let mut schedule = Schedule::new(config.pace);
+    let mut frames = 0;
+    for day in source {
+        let (date, mut rows) = day?;
+        if rows.is_empty() { continue; }
+        if config.end.is_some_and(|x| date > x) {
+            break;
+        }
+        if !config.includes_date(date) {
+            continue;
+        }
+        // 範囲外の値を含むレコードのせいでフレームごと送れなくならないよう、その値だけを落とす
+        for err in drop_out_of_range(config.protocol_version, &mut rows) {
+            println!("Dropped on {date}: {err}");
+        }
+        let data_to_send = match encode_frame(config.protocol_version, date, &rows) {
+            // 旧形式では 1970 年より前の日付を送れないので飛ばす
+            Err(EncodeError::DateOutOfRange(_)) => continue,
+            x => x?,
+        };
+        if let Some(date) = control.tick(seen_generation, schedule.wait(date)) {
+            return Ok(ReplayEnd::Seek(date));
+        }
+        send(data_to_send)?;
+        frames += 1;
+    }
+    Ok(ReplayEnd::Finished { frames })
 }
 
+// 再開を頼まれたら、その翌日と --start の遅い方から始める
+fn session_start(start: Option<NaiveDate>, resume: Option<NaiveDate>) -> Option<NaiveDate> {
+    start.max(resume.and_then(|x| x.succ_opt()))
+}
 
-fn run_process(mut stream: TcpStream, observation_points: &Vec<ObservationPoint>) -> Result<(), anyhow::Error> {
-    let observation_points: Vec<_> = observation_points.iter().filter(|x| x.path().is_dir()).collect();
-    for decade in DECADES {
-        let mut readers = observation_points.iter().map(|point| {
-            let mut path = point.path();
-            path.push(decade.to_string() + ".csv");
-            let reader = Reader::from_path(path).unwrap();
-            (point.id(), reader)
-        }).collect::<Vec<_>>();
-        let mut records = readers.iter_mut()
-            .map(|(n, reader)| (*n, reader.records()))
-            .collect::<Vec<_>>();
-        'outer: loop {
-            thread::sleep(Duration::from_millis(INTERVAL_MILLS));
-            let mut date = None;
-            let mut rows = Vec::new();
-            for (n, reader) in records.iter_mut() {
-                let value = reader.next();
-                if let Some(data) = value {
-                    let data = data?;
-                    if data[1].is_empty() { continue; }
-
-                    date.get_or_insert(NaiveDate::parse_from_str(&data[0], "%Y/%m/%d")?);
-                    rows.push(ObservationPointData::new(*n, &data[1], &data[2], &data[3]));
-                } else {
-                    break 'outer;
-                }
+// ループで戻るのは再開した日付ではなく --start
+fn run_process(
+    mut send: impl FnMut(Vec<u8>) -> Result<(), anyhow::Error>,
+    observation_points: &[ObservationPoint],
+    config: &Config,
+    control: &Control,
+    mut seen_generation: u64,
+    resume: Option<NaiveDate>,
+) -> Result<(), anyhow::Error> {
+    let mut current = config.clone();
+    current.start = session_start(config.start, resume);
+    loop {
+        match replay(&mut send, observation_points, &current, control, &mut seen_generation)? {
+            ReplayEnd::Seek(date) => {
+                println!("Seek to {date}");
+                current.start = Some(date);
+            }
+            // 最初から送っても 1 フレームも送れなかったときは繰り返さない
+            ReplayEnd::Finished { frames } if config.r#loop && (frames > 0 || current.start != config.start) => {
+                println!("Looping back to the start");
+                current.start = config.start;
             }
-            let data_to_send = compress_data(date.unwrap(), &rows);
-            stream.write_all(&data_to_send)?;
+            ReplayEnd::Finished { .. } => return Ok(()),
         }
     }
-    Ok(())
+}
+
+// 接続直後にクライアントが待つ時間
+const RESUME_TIMEOUT: Duration = Duration::from_millis(200);
+
+// 接続直後に "resume YYYY-MM-DD" が送られてきたら、その日付を返す（その翌日から再生する）
+// 何も送ってこないクライアントには RESUME_TIMEOUT だけ待ってから送り始める
+fn read_resume_request(socket: &TcpStream) -> Option<NaiveDate> {
+    socket.set_read_timeout(Some(RESUME_TIMEOUT)).ok()?;
+    let mut line = String::new();
+    let result = BufReader::new(socket).read_line(&mut line);
+    socket.set_read_timeout(None).ok()?;
+    result.ok()?;
+    line.trim().strip_prefix("resume ")?.parse().ok()
 }
 
 fn main() -> Result<(), anyhow::Error> {
-    let observation_points = load_observation_points("data/observation.csv")?;
+    let config = Arc::new(Config::from_args()?);
+    let observation_points = Arc::new(load_observation_points(config.data_dir.join("observation.csv"))?);
+    let broadcaster = Arc::new(Broadcaster::default());
+    let control = Arc::new(Control::new(config.speed));
+    if let Some(addr) = config.control {
+        control.clone().listen(addr)?;
+    }
 
-    let listener = TcpListener::bind(SocketAddrV4::new(Ipv4Addr::LOCALHOST, PORT))?;
+    let listener = TcpListener::bind(config.bind)?;
     println!("Listening on {}", listener.local_addr()?);
 
     loop {
-        let (socket, addr) = listener.accept()?;
+        let (mut socket, addr) = listener.accept()?;
         println!("Accepted connection from {}", addr);
-        if run_process(socket, &observation_points).is_ok() {
-            println!("Completed")
-        } else {
-            println!("Disconnected")
+        let (config, observation_points, control) = (config.clone(), observation_points.clone(), control.clone());
+        match config.clock {
+            Clock::PerClient => {
+                thread::spawn(move || {
+                    // 0 から始めると、前にシークされていれば新しい接続もその日付から再生する
+                    // 再開を頼まれたときは、それより前のシークで再開位置を上書きしない
+                    let mut seen_generation = 0;
+                    let resume = read_resume_request(&socket);
+                    if let Some(date) = resume {
+                        println!("Resuming {addr} after {date}");
+                        seen_generation = control.generation();
+                    }
+                    let mut injector = FaultInjector::new(config.faults);
+                    let send = |frame: Vec<u8>| injector.send(&frame, |x| Ok(socket.write_all(x)?));
+                    // 最後まで送ったら、遅延中に溜めた分も送る
+                    if run_process(send, &observation_points, &config, &control, seen_generation, resume)
+                        .and_then(|_| injector.flush(|x| Ok(socket.write_all(x)?)))
+                        .is_ok() {
+                        println!("Completed {addr}")
+                    } else {
+                        println!("Disconnected {addr}")
+                    }
+                });
+            }
+            Clock::Shared => {
+                // 最初のクライアントが来たら再生を始め、終わるまで全員に同じフレームを配る
+                if broadcaster.subscribe(socket, config.faults) {
+                    let broadcaster = broadcaster.clone();
+                    thread::spawn(move || {
+                        loop {
+                            let mut frames = 0;
+                            let send = |frame| {
+                                broadcaster.send(frame);
+                                frames += 1;
+                                Ok(())
+                            };
+                            if let Err(err) = run_process(send, &observation_points, &config, &control, 0, None) {
+                                println!("Replay failed: {err}");
+                            }
+                            println!("Completed");
+                            // 1 フレームも送れなかった再生はやり直さない
+                            if !broadcaster.finish(frames > 0) {
+                                break;
+                            }
+                            println!("Restarting for clients that joined at the end");
+                        }
+                    });
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::StationFilter;
+    use server::decompress_data;
+
+    fn date(month: u32, day: u32) -> NaiveDate {
+        NaiveDate::from_ymd_opt(2000, month, day).unwrap()
+    }
+
+    // count フレームを受け取るまで再生し、その日付を返す
+    fn replay_dates(config: &Config, resume: Option<NaiveDate>, count: usize) -> Vec<NaiveDate> {
+        let points = load_observation_points("data/observation.csv").unwrap();
+        let control = Control::new(1e6);
+        let mut dates = Vec::new();
+        let send = |frame: Vec<u8>| {
+            dates.push(decompress_data(&frame)?.0);
+            anyhow::ensure!(dates.len() < count, "enough frames");
+            Ok(())
+        };
+        let _ = run_process(send, &points, config, &control, 0, resume);
+        dates
+    }
+
+    fn config(start: NaiveDate, end: NaiveDate) -> Config {
+        Config {
+            source: Source::Synthetic,
+            stations: vec![StationFilter::Id(40046)],
+            start: Some(start),
+            end: Some(end),
+            ..Config::default()
         }
     }
+
+    #[test]
+    fn resume_then_loop_goes_back_to_start() {
+        let config = Config { r#loop: true, ..config(date(1, 1), date(1, 5)) };
+        let expected = [date(1, 4), date(1, 5), date(1, 1), date(1, 2), date(1, 3), date(1, 4), date(1, 5), date(1, 1)];
+        assert_eq!(replay_dates(&config, Some(date(1, 3)), expected.len()), expected);
+    }
+
+    #[test]
+    fn resume_past_end_loops_back_to_start() {
+        let config = Config { r#loop: true, ..config(date(1, 1), date(1, 3)) };
+        assert_eq!(replay_dates(&config, Some(date(2, 1)), 2), [date(1, 1), date(1, 2)]);
+    }
+
+    #[test]
+    fn later_start_wins_over_resume() {
+        let config = config(date(1, 10), date(1, 12));
+        assert_eq!(replay_dates(&config, Some(date(1, 2)), 10), [date(1, 10), date(1, 11), date(1, 12)]);
+        assert_eq!(replay_dates(&config, Some(date(1, 10)), 10), [date(1, 11), date(1, 12)]);
+    }
 }
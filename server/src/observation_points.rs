@@ -28,8 +28,8 @@ impl ObservationPoint {
     pub fn longitude(&self) -> f32 { self.longitude }
     pub fn altitude(&self) -> i32 { self.altitude }
     pub fn is_prefecture_center(&self) -> bool { self.is_prefecture_center }
-    pub fn path(&self) -> PathBuf {
-        let mut ret = PathBuf::from("data");
+    pub fn path(&self, data_dir: &Path) -> PathBuf {
+        let mut ret = data_dir.to_path_buf();
         ret.push(&self.name);
         ret
     }
@@ -38,7 +38,7 @@ impl ObservationPoint {
         degrees as f32 + minutes / 60.0
     }
 
-    fn from_csv_row(row: &Vec<&str>) -> Self {
+    fn from_csv_row(row: &[&str]) -> Self {
         Self {
             prefecture: row[0].to_string(),
             id: row[1].parse().unwrap(),
@@ -59,7 +59,7 @@ pub fn load_observation_points<P: AsRef<Path>>(path: P) -> anyhow::Result<Vec<Ob
     let mut observation_points = vec![];
     for row in reader.records() {
         let row = row?;
-        let point = ObservationPoint::from_csv_row(&row.into_iter().collect());
+        let point = ObservationPoint::from_csv_row(&row.into_iter().collect::<Vec<_>>());
         observation_points.push(point)
     }
     Ok(observation_points)
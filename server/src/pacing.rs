@@ -0,0 +1,72 @@
+use chrono::{Local, Months, NaiveDate, TimeZone};
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+// 実時間と過去データの日付の対応付け
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum Pacing {
+    // 一定間隔で送る（--speed に従う）
+    Fast,
+    // N 年前の今日のデータを、実際の日付が変わるごとに送る
+    YearsAgo(u32),
+    // データの 1 年を実時間のこの長さに縮めて送る
+    YearPer(Duration),
+}
+
+// 500ms, 30s, 10m, 1h, 2d のような長さ
+pub(crate) fn parse_duration(s: &str) -> anyhow::Result<Duration> {
+    let (value, unit) = [("ms", 0.001), ("s", 1.0), ("m", 60.0), ("h", 60.0 * 60.0), ("d", 24.0 * 60.0 * 60.0)]
+        .into_iter()
+        .find_map(|(suffix, unit)| Some((s.strip_suffix(suffix)?, unit)))
+        .ok_or_else(|| anyhow::anyhow!("duration needs a unit (ms, s, m, h, d): {s}"))?;
+    let value: f64 = value.parse()?;
+    anyhow::ensure!(value > 0.0, "duration must be positive: {s}");
+    Ok(Duration::from_secs_f64(value * unit))
+}
+
+impl FromStr for Pacing {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            None if s == "fast" => Ok(Pacing::Fast),
+            Some(("years-ago", n)) => Ok(Pacing::YearsAgo(n.parse()?)),
+            Some(("year-per", x)) => Ok(Pacing::YearPer(parse_duration(x)?)),
+            _ => Err(anyhow::anyhow!("unknown pace: {s}")),
+        }
+    }
+}
+
+// 1 回の再生の中で、各日付を送る時刻を決める
+pub(crate) struct Schedule {
+    pacing: Pacing,
+    // YearPer で最初に送った日付とその時刻
+    origin: Option<(NaiveDate, Instant)>,
+}
+
+impl Schedule {
+    pub fn new(pacing: Pacing) -> Self {
+        Self { pacing, origin: None }
+    }
+
+    // date を送るまでに待つ時間。None なら --speed による一定間隔
+    // 予定の時刻を過ぎている日付（起動時に今日より前の分など）は待たずに送る
+    pub fn wait(&mut self, date: NaiveDate) -> Option<Duration> {
+        match self.pacing {
+            Pacing::Fast => None,
+            Pacing::YearsAgo(years) => {
+                let Some(due) = date.checked_add_months(Months::new(years * 12))
+                    .and_then(|x| Local.from_local_datetime(&x.and_hms_opt(0, 0, 0).unwrap()).earliest()) else {
+                    return Some(Duration::ZERO);
+                };
+                Some((due - Local::now()).to_std().unwrap_or_default())
+            }
+            Pacing::YearPer(year) => {
+                let (start, at) = *self.origin.get_or_insert((date, Instant::now()));
+                let days = (date - start).num_days().max(0) as f64;
+                let due = at + year.mul_f64(days / 365.2425);
+                Some(due.saturating_duration_since(Instant::now()))
+            }
+        }
+    }
+}
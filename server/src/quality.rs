@@ -0,0 +1,97 @@
+use std::str::FromStr;
+
+// 気象庁 CSV の品質情報
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[repr(u8)]
+pub enum Quality {
+    // 観測値なし・統計対象外
+    NotObserved = 0,
+    // 欠測 (×)
+    Missing = 1,
+    // 疑問値 (#)
+    Suspect = 2,
+    // 資料不足値 (])
+    Insufficient = 4,
+    // 準正常値 ())
+    QuasiNormal = 5,
+    // 正常値
+    Normal = 8,
+}
+
+impl Quality {
+    pub fn from_code(code: u8) -> Option<Self> {
+        match code {
+            0 => Some(Quality::NotObserved),
+            1 => Some(Quality::Missing),
+            2 => Some(Quality::Suspect),
+            4 => Some(Quality::Insufficient),
+            5 => Some(Quality::QuasiNormal),
+            8 => Some(Quality::Normal),
+            _ => None,
+        }
+    }
+
+    // 値の末尾に付く記号から品質を推定する（品質情報の列がない CSV 用）
+    pub fn from_symbol(value: &str) -> Option<Self> {
+        let value = value.trim();
+        if value.is_empty() {
+            None
+        } else if value.contains('×') {
+            Some(Quality::Missing)
+        } else if value.ends_with('#') {
+            Some(Quality::Suspect)
+        } else if value.ends_with(']') {
+            Some(Quality::Insufficient)
+        } else if value.ends_with(')') {
+            Some(Quality::QuasiNormal)
+        } else if value.starts_with("//") {
+            Some(Quality::NotObserved)
+        } else {
+            None
+        }
+    }
+}
+
+impl FromStr for Quality {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "not-observed" => Ok(Quality::NotObserved),
+            "missing" => Ok(Quality::Missing),
+            "suspect" => Ok(Quality::Suspect),
+            "insufficient" => Ok(Quality::Insufficient),
+            "quasi-normal" => Ok(Quality::QuasiNormal),
+            "normal" => Ok(Quality::Normal),
+            _ => Err(anyhow::anyhow!("unknown quality: {s}")),
+        }
+    }
+}
+
+// フィールドごとの品質情報と均質番号（どちらも不明なら None）
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct QualityInfo {
+    pub quality: Option<Quality>,
+    pub homogeneity: Option<u8>,
+}
+
+impl QualityInfo {
+    pub fn is_known(&self) -> bool {
+        self.quality.is_some() || self.homogeneity.is_some()
+    }
+
+    // 品質 (不明は 0xFF) と均質番号 (不明は 0) の 2 バイト
+    pub(crate) fn encode(&self) -> [u8; 2] {
+        [
+            self.quality.map_or(0xFF, |x| x as u8),
+            self.homogeneity.unwrap_or(0),
+        ]
+    }
+
+    pub(crate) fn decode(data: [u8; 2]) -> Self {
+        Self {
+            quality: Quality::from_code(data[0]),
+            homogeneity: (data[1] != 0).then_some(data[1]),
+        }
+    }
+}
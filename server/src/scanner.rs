@@ -0,0 +1,223 @@
+use crate::frame::{frame_length, verify_frame, FrameHeader, FrameLength, CRC_LEN, FLAG_CRC32, HEADER_LEN, MAGIC, MAX_PAYLOAD_LEN};
+
+// TCP ストリームからフレームを切り出す
+// CRC の合わないフレームやマジックナンバーで始まらないバイト列を見つけたら、
+// 次のマジックナンバーまで読み飛ばして同期を取り直す
+pub struct FrameScanner {
+    buffer: Vec<u8>,
+    // ヘッダ付きのフレームを一度でも受け取ったか（それまでは旧形式のストリームとして扱う）
+    headered: bool,
+    // CRC 付きのフレームを一度でも受け取ったか（以降は CRC のないフレームを壊れたものとみなす）
+    checksummed: bool,
+    // 同期中に壊れたデータに出会ったか
+    resyncing: bool,
+    dropped_frames: usize,
+    skipped_bytes: usize,
+}
+
+impl FrameScanner {
+    pub fn new() -> Self {
+        Self {
+            buffer: Vec::new(),
+            headered: false,
+            checksummed: false,
+            resyncing: false,
+            dropped_frames: 0,
+            skipped_bytes: 0,
+        }
+    }
+
+    // 壊れていたため捨てたフレームの数（同期を取り直した回数）
+    pub fn dropped_frames(&self) -> usize {
+        self.dropped_frames
+    }
+
+    pub fn skipped_bytes(&self) -> usize {
+        self.skipped_bytes
+    }
+
+    pub fn push(&mut self, data: &[u8]) {
+        self.buffer.extend_from_slice(data);
+    }
+
+    fn skip(&mut self, len: usize) {
+        if !self.resyncing {
+            self.resyncing = true;
+            self.dropped_frames += 1;
+        }
+        self.skipped_bytes += len;
+        self.buffer.drain(..len);
+    }
+
+    // 先頭を 1 バイト以上捨てて、次のマジックナンバーの候補まで進める
+    fn skip_to_next_magic(&mut self) {
+        let next = self.buffer[1..].windows(MAGIC.len())
+            .position(|x| x == MAGIC)
+            .map_or(self.buffer.len().saturating_sub(MAGIC.len() - 1).max(1), |i| i + 1);
+        self.skip(next);
+    }
+
+    // 先頭のフレームの途中に、CRC の合う完全なフレームが埋まっている位置
+    // 長さのフィールドが壊れたフレームを待ち続けないために使う
+    fn find_embedded_frame(&self) -> Option<usize> {
+        (1..self.buffer.len().saturating_sub(HEADER_LEN)).find(|&i| {
+            let rest = &self.buffer[i..];
+            let Some(header) = FrameHeader::decode(rest) else { return false };
+            header.flags & FLAG_CRC32 != 0
+                && rest.len() >= header.frame_len()
+                && verify_frame(&rest[..header.frame_len()]).is_ok()
+        })
+    }
+
+    // 次の正しいフレームを取り出す。データが足りなければ None
+    pub fn next_frame(&mut self) -> Option<Vec<u8>> {
+        loop {
+            if self.buffer.is_empty() {
+                return None;
+            }
+            let has_magic = self.buffer.starts_with(&MAGIC[..self.buffer.len().min(MAGIC.len())]);
+            if self.headered && !has_magic {
+                self.skip_to_next_magic();
+                continue;
+            }
+            let total = match frame_length(&self.buffer) {
+                FrameLength::NeedMore(_) => return None,
+                FrameLength::Known(n) => n,
+            };
+            let header = FrameHeader::decode(&self.buffer);
+            if total > HEADER_LEN + MAX_PAYLOAD_LEN + CRC_LEN
+                || (self.checksummed && header.is_some_and(|x| x.flags & FLAG_CRC32 == 0)) {
+                self.skip_to_next_magic();
+                continue;
+            }
+            if self.buffer.len() < total {
+                match self.find_embedded_frame() {
+                    Some(i) if self.checksummed => {
+                        self.skip(i);
+                        continue;
+                    }
+                    _ => return None,
+                }
+            }
+            if verify_frame(&self.buffer[..total]).is_err() {
+                self.skip_to_next_magic();
+                continue;
+            }
+            if let Some(header) = header {
+                self.headered = true;
+                self.checksummed |= header.flags & FLAG_CRC32 != 0;
+            }
+            self.resyncing = false;
+            return Some(self.buffer.drain(..total).collect());
+        }
+    }
+}
+
+impl Default for FrameScanner {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::element::Element;
+    use crate::frame::{encode_frame, VERSION_3, VERSION_LEGACY};
+    use crate::ObservationPointData;
+    use chrono::NaiveDate;
+
+    fn frame(version: u8, day: u32) -> Vec<u8> {
+        let mut point = ObservationPointData::new(47662);
+        point.set_value(Element::AverageTemperature, Some(day as f64));
+        point.set_value(Element::MaxTemperature, Some(30.0));
+        point.set_value(Element::MinTemperature, Some(-5.5));
+        encode_frame(version, NaiveDate::from_ymd_opt(2023, 8, day).unwrap(), &[point]).unwrap()
+    }
+
+    // 1 バイトずつ渡して、取り出せたフレームをすべて返す
+    fn scan(scanner: &mut FrameScanner, stream: &[u8]) -> Vec<Vec<u8>> {
+        let mut res = Vec::new();
+        for &byte in stream {
+            scanner.push(&[byte]);
+            while let Some(frame) = scanner.next_frame() {
+                res.push(frame);
+            }
+        }
+        res
+    }
+
+    #[test]
+    fn passes_through_valid_frames() {
+        let frames = [frame(VERSION_3, 1), frame(VERSION_3, 2), frame(VERSION_3, 3)];
+        let mut scanner = FrameScanner::new();
+        assert_eq!(scan(&mut scanner, &frames.concat()), frames);
+        assert_eq!(scanner.dropped_frames(), 0);
+        assert_eq!(scanner.skipped_bytes(), 0);
+    }
+
+    #[test]
+    fn passes_through_legacy_frames() {
+        let frames = [frame(VERSION_LEGACY, 1), frame(VERSION_LEGACY, 2)];
+        let mut scanner = FrameScanner::new();
+        assert_eq!(scan(&mut scanner, &frames.concat()), frames);
+        assert_eq!(scanner.dropped_frames(), 0);
+    }
+
+    #[test]
+    fn recovers_from_truncated_frame() {
+        let [a, b, c] = [frame(VERSION_3, 1), frame(VERSION_3, 2), frame(VERSION_3, 3)];
+        let stream = [&a[..], &b[..b.len() / 2], &c[..]].concat();
+        let mut scanner = FrameScanner::new();
+        assert_eq!(scan(&mut scanner, &stream), [a, c]);
+        assert_eq!(scanner.dropped_frames(), 1);
+        assert_eq!(scanner.skipped_bytes(), b.len() / 2);
+    }
+
+    #[test]
+    fn recovers_from_truncated_length_field() {
+        let [a, b, c] = [frame(VERSION_3, 1), frame(VERSION_3, 2), frame(VERSION_3, 3)];
+        let mut broken = b.clone();
+        // 長さのフィールドを大きくして、後ろのフレームを飲み込ませる
+        broken[HEADER_LEN - 1] ^= 0x40;
+        let stream = [&a[..], &broken[..], &c[..]].concat();
+        let mut scanner = FrameScanner::new();
+        assert_eq!(scan(&mut scanner, &stream), [a, c]);
+        assert_eq!(scanner.dropped_frames(), 1);
+    }
+
+    #[test]
+    fn recovers_from_bit_flip() {
+        let [a, b, c] = [frame(VERSION_3, 1), frame(VERSION_3, 2), frame(VERSION_3, 3)];
+        for i in [2, HEADER_LEN, b.len() - 1] {
+            let mut broken = b.clone();
+            broken[i] ^= 0x01;
+            let stream = [&a[..], &broken[..], &c[..]].concat();
+            let mut scanner = FrameScanner::new();
+            assert_eq!(scan(&mut scanner, &stream), [a.clone(), c.clone()], "bit flip at {i}");
+            assert_eq!(scanner.dropped_frames(), 1, "bit flip at {i}");
+            assert_eq!(scanner.skipped_bytes(), b.len(), "bit flip at {i}");
+        }
+    }
+
+    #[test]
+    fn recovers_from_stray_bytes() {
+        let [a, b, c] = [frame(VERSION_3, 1), frame(VERSION_3, 2), frame(VERSION_3, 3)];
+        // マジックナンバーの片割れも混ぜる
+        let stray = [0x00, MAGIC[0], 0xFF, MAGIC[0], MAGIC[1], 0x42];
+        let stream = [&a[..], &stray[..], &b[..], &stray[..2], &c[..]].concat();
+        let mut scanner = FrameScanner::new();
+        assert_eq!(scan(&mut scanner, &stream), [a, b, c]);
+        assert_eq!(scanner.dropped_frames(), 2);
+        assert_eq!(scanner.skipped_bytes(), stray.len() + 2);
+    }
+
+    #[test]
+    fn waits_for_rest_of_frame() {
+        let a = frame(VERSION_3, 1);
+        let mut scanner = FrameScanner::new();
+        assert!(scan(&mut scanner, &a[..a.len() - 1]).is_empty());
+        assert_eq!(scan(&mut scanner, &a[a.len() - 1..]), [a]);
+        assert_eq!(scanner.dropped_frames(), 0);
+    }
+}
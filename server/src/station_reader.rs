@@ -0,0 +1,139 @@
+use chrono::NaiveDate;
+use csv::{ReaderBuilder, StringRecordsIntoIter};
+use crate::daily_csv::DailyCsvLayout;
+use crate::observation_points::ObservationPoint;
+use crate::ObservationPointData;
+use std::fs::File;
+use std::iter::Peekable;
+use std::path::{Path, PathBuf};
+
+// 地点のディレクトリにある 10 年ごとの CSV（1970.csv など）を年の順に列挙する
+pub fn decade_files(dir: &Path) -> anyhow::Result<Vec<(u32, PathBuf)>> {
+    let mut res = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().is_none_or(|x| x != "csv") {
+            continue;
+        }
+        if let Some(decade) = path.file_stem().and_then(|x| x.to_str()).and_then(|x| x.parse().ok()) {
+            res.push((decade, path));
+        }
+    }
+    res.sort();
+    Ok(res)
+}
+
+// 1 地点の日別値 CSV を日付順に読む
+// 複数のファイルを渡すと、順につないで 1 本の時系列として読む
+pub struct StationReader {
+    id: u32,
+    files: std::vec::IntoIter<PathBuf>,
+    current: Option<(DailyCsvLayout, Peekable<StringRecordsIntoIter<File>>)>,
+    // 次に返す行
+    head: Option<(NaiveDate, ObservationPointData)>,
+}
+
+impl StationReader {
+    pub fn open(id: u32, files: Vec<PathBuf>) -> anyhow::Result<Self> {
+        let mut res = Self { id, files: files.into_iter(), current: None, head: None };
+        res.advance()?;
+        Ok(res)
+    }
+
+    fn advance(&mut self) -> anyhow::Result<()> {
+        loop {
+            let Some((layout, records)) = &mut self.current else {
+                let Some(path) = self.files.next() else {
+                    self.head = None;
+                    return Ok(());
+                };
+                // 前置きの行の長さはまちまちなので、列の数は揃っていなくてよい
+                let mut records = ReaderBuilder::new().has_headers(false).flexible(true).from_path(path)?.into_records().peekable();
+                let mut headers = Vec::new();
+                while let Some(row) = records.next_if(|x| x.as_ref().is_ok_and(|x| !DailyCsvLayout::is_data_row(x))) {
+                    headers.push(row?);
+                }
+                self.current = Some((DailyCsvLayout::from_header_rows(&headers)?, records));
+                continue;
+            };
+            match records.next() {
+                Some(row) => {
+                    self.head = Some(layout.parse(self.id, &row?)?);
+                    return Ok(());
+                }
+                None => self.current = None,
+            }
+        }
+    }
+
+    pub fn peek_date(&self) -> Option<NaiveDate> {
+        self.head.as_ref().map(|(date, _)| *date)
+    }
+
+    // 次の行が date のものなら取り出す
+    fn next_if(&mut self, date: NaiveDate) -> anyhow::Result<Option<ObservationPointData>> {
+        if self.peek_date() != Some(date) {
+            return Ok(None);
+        }
+        let (_, row) = self.head.take().unwrap();
+        self.advance()?;
+        Ok(Some(row))
+    }
+}
+
+// 各地点の次の行を日付で突き合わせ、いちばん早い日付の行をまとめて返す
+// 欠測しかない行は含めない（その日に観測のあった地点だけになる）
+pub struct CsvSource {
+    readers: Vec<StationReader>,
+}
+
+impl CsvSource {
+    pub fn new(readers: Vec<StationReader>) -> Self {
+        Self { readers }
+    }
+
+    // data_dir の下の地点ごとのディレクトリから、include_decade を満たす年のファイルをすべてつないで読む
+    pub fn open<'a>(
+        observation_points: impl Iterator<Item = &'a ObservationPoint>,
+        data_dir: &Path,
+        include_decade: impl Fn(u32) -> bool,
+    ) -> anyhow::Result<Self> {
+        let mut readers = Vec::new();
+        for point in observation_points {
+            let dir = point.path(data_dir);
+            if !dir.is_dir() {
+                continue;
+            }
+            let files: Vec<_> = decade_files(&dir)?.into_iter()
+                .filter(|(decade, _)| include_decade(*decade))
+                .map(|(_, path)| path)
+                .collect();
+            if !files.is_empty() {
+                readers.push(StationReader::open(point.id(), files)?);
+            }
+        }
+        Ok(Self::new(readers))
+    }
+
+    fn next_day(&mut self) -> anyhow::Result<Option<(NaiveDate, Vec<ObservationPointData>)>> {
+        let Some(date) = self.readers.iter().filter_map(StationReader::peek_date).min() else {
+            return Ok(None);
+        };
+        let mut rows = Vec::new();
+        for reader in &mut self.readers {
+            if let Some(row) = reader.next_if(date)?
+                && !row.is_empty() {
+                rows.push(row);
+            }
+        }
+        Ok(Some((date, rows)))
+    }
+}
+
+impl Iterator for CsvSource {
+    type Item = anyhow::Result<(NaiveDate, Vec<ObservationPointData>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_day().transpose()
+    }
+}
@@ -0,0 +1,133 @@
+use crate::rng::{mix, to_unit};
+use chrono::{Datelike, NaiveDate};
+use server::element::Element;
+use server::observation_points::ObservationPoint;
+use server::ObservationPointData;
+use std::f64::consts::PI;
+
+// 乱数はすべて (シード, 地点, 日付) から決まるハッシュで作る
+// 途中の日付から始めたりシークしたりしても同じ値になる
+fn hash(keys: &[u64]) -> u64 {
+    keys.iter().fold(0, |acc, &x| mix(acc ^ x))
+}
+
+// [0, 1)
+fn uniform(keys: &[u64]) -> f64 {
+    to_unit(hash(keys))
+}
+
+// 標準正規分布（Box-Muller）
+fn normal(keys: &[u64]) -> f64 {
+    let u1 = uniform(keys).max(f64::MIN_POSITIVE);
+    let u2 = uniform(&[hash(keys), 1]);
+    (-2.0 * u1.ln()).sqrt() * (2.0 * PI * u2).cos()
+}
+
+// 数日単位でゆっくり変わる揺らぎ（PERIOD 日ごとの乱数を線形補間する）
+fn smooth_noise(keys: [u64; 2], day: i64) -> f64 {
+    const PERIOD: i64 = 4;
+    let (block, offset) = (day.div_euclid(PERIOD), day.rem_euclid(PERIOD));
+    let a = normal(&[keys[0], keys[1], block as u64]);
+    let b = normal(&[keys[0], keys[1], block as u64 + 1]);
+    let t = offset as f64 / PERIOD as f64;
+    a * (1.0 - t) + b * t
+}
+
+// 熱波・寒波の期間
+const EVENT_BLOCK: i64 = 30;
+const EVENT_PROBABILITY: f64 = 0.2;
+
+// 地点ごとの気候（緯度・標高から決める）
+struct Climate {
+    id: u32,
+    // 年平均気温
+    mean: f64,
+    // 季節変化の振幅
+    amplitude: f64,
+    // 日較差
+    range: f64,
+}
+
+impl Climate {
+    fn new(point: &ObservationPoint) -> Self {
+        let latitude = point.latitude() as f64;
+        Self {
+            id: point.id(),
+            // 那覇で 23 ℃ 前後、札幌で 9 ℃ 前後になるように
+            mean: 23.3 - 0.83 * (latitude - 26.2) - 0.0065 * point.altitude() as f64,
+            amplitude: 6.0 + 0.45 * (latitude - 26.2),
+            range: 7.0 + 0.1 * (latitude - 26.2),
+        }
+    }
+}
+
+// 季節変化と揺らぎを持つ気温をそれらしく作る
+pub(crate) struct SyntheticSource {
+    seed: u64,
+    climates: Vec<Climate>,
+    date: NaiveDate,
+    end: Option<NaiveDate>,
+    // 熱波・寒波を入れるか
+    events: bool,
+}
+
+impl SyntheticSource {
+    pub fn new<'a>(points: impl Iterator<Item = &'a ObservationPoint>, seed: u64, start: NaiveDate, end: Option<NaiveDate>, events: bool) -> Self {
+        Self { seed, climates: points.map(Climate::new).collect(), date: start, end, events }
+    }
+
+    // 全国で共通の熱波 (+) ・寒波 (-) による偏差
+    fn event_anomaly(&self, day: i64) -> f64 {
+        if !self.events {
+            return 0.0;
+        }
+        let block = day.div_euclid(EVENT_BLOCK);
+        let keys = [self.seed, u64::MAX, block as u64];
+        if uniform(&keys) >= EVENT_PROBABILITY {
+            return 0.0;
+        }
+        let length = 3 + (hash(&[hash(&keys), 1]) % 6) as i64;
+        let start = (hash(&[hash(&keys), 2]) % (EVENT_BLOCK - length) as u64) as i64;
+        let offset = day.rem_euclid(EVENT_BLOCK);
+        if offset < start || offset >= start + length {
+            return 0.0;
+        }
+        // 夏は熱波、冬は寒波
+        let month = self.date.month();
+        if (5..=9).contains(&month) { 5.0 } else if month <= 2 || month == 12 { -6.0 } else { 0.0 }
+    }
+
+    fn generate(&self, climate: &Climate, day: i64, anomaly: f64) -> ObservationPointData {
+        let keys = [self.seed, climate.id as u64];
+        // 1 月下旬がいちばん寒い
+        let season = -(2.0 * PI * (self.date.ordinal() as f64 - 25.0) / 365.25).cos();
+        let average = climate.mean + climate.amplitude * season
+            + 2.5 * smooth_noise(keys, day)
+            + 1.0 * normal(&[keys[0], keys[1], day as u64])
+            + anomaly;
+        let range = (climate.range + 1.5 * normal(&[keys[0], keys[1], day as u64, 1])).max(1.0);
+        let skew = 0.1 * normal(&[keys[0], keys[1], day as u64, 2]);
+
+        let mut data = ObservationPointData::new(climate.id);
+        data.set_value(Element::AverageTemperature, Some(average));
+        data.set_value(Element::MaxTemperature, Some(average + range * (0.5 + skew)));
+        data.set_value(Element::MinTemperature, Some(average - range * (0.5 - skew)));
+        data
+    }
+}
+
+impl Iterator for SyntheticSource {
+    type Item = anyhow::Result<(NaiveDate, Vec<ObservationPointData>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.end.is_some_and(|x| self.date > x) {
+            return None;
+        }
+        let day = (self.date - NaiveDate::from_ymd_opt(1970, 1, 1).unwrap()).num_days();
+        let anomaly = self.event_anomaly(day);
+        let rows = self.climates.iter().map(|x| self.generate(x, day, anomaly)).collect();
+        let date = self.date;
+        self.date = date.succ_opt()?;
+        Some(Ok((date, rows)))
+    }
+}
@@ -1,69 +1,140 @@
 use crate::AppState;
 use bytes::Bytes;
 use chrono::{Datelike, NaiveDate};
-use serde::{Serialize, Serializer};
-use server::decompress_data;
+use serde::de::IgnoredAny;
+use serde::{Deserialize, Serialize, Serializer};
+use server::{decompress_data, element::Element, quality::Quality, DecodeError, ObservationPointData};
 use std::collections::BTreeMap;
+use std::fmt;
 use std::sync::Arc;
 use crate::prefecture::get_prefecture_code;
-use crate::window_aggregator::WindowAggregator;
+use crate::threshold::{ThresholdCounts, ThresholdRules, ThresholdStreaks};
+use crate::normal_aggregator::{Anomaly, AnomalyResults, BlockTotals, NormalAggregator};
+use crate::record_aggregator::{NewRecord, RecordSet, RecordTables};
+use crate::window_aggregator::{PointWindowSnapshot, WindowAggregateResult, WindowAggregator};
 
-#[derive(Clone, Debug)]
+// 気温以外の要素（降水量・日照時間など）の集計
+#[derive(Clone, Debug, Deserialize)]
+struct ElementAggregateResult {
+    min: f64,
+    max: f64,
+    sum: f64,
+    count: usize,
+}
+
+impl ElementAggregateResult {
+    fn new() -> Self {
+        Self { min: f64::MAX, max: f64::MIN, sum: 0.0, count: 0 }
+    }
+
+    fn add(&mut self, value: f64) {
+        self.min = self.min.min(value);
+        self.max = self.max.max(value);
+        self.sum += value;
+        self.count += 1;
+    }
+
+    fn average(&self) -> Option<f64> {
+        if self.count == 0 { None }
+        else { Some(self.sum / self.count as f64) }
+    }
+}
+
+impl Serialize for ElementAggregateResult {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer
+    {
+        use serde::ser::SerializeStruct;
+        let mut state = serializer.serialize_struct("ElementAggregateResult", 5)?;
+        state.serialize_field("min", &self.min)?;
+        state.serialize_field("max", &self.max)?;
+        state.serialize_field("sum", &self.sum)?;
+        state.serialize_field("count", &self.count)?;
+        state.serialize_field("average", &self.average())?;
+        state.end()
+    }
+}
+
+// キーは Element::name() としてシリアライズされる
+type ElementAggregateMap = BTreeMap<Element, ElementAggregateResult>;
+
+fn add_elements(map: &mut ElementAggregateMap, data: &ObservationPointData) {
+    for element in data.elements().iter().filter(|x| !x.is_temperature()) {
+        if let Some(value) = data.get(element) {
+            map.entry(element).or_insert_with(ElementAggregateResult::new).add(value);
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(from = "MonthAggregateFields")]
 struct MonthAggregateResult {
     min: f64,
     max: f64,
     sum: f64,
     count: usize,
-    // 猛暑日
-    high_over_35: usize,
-    // 真夏日
-    high_over_30: usize,
-    // 夏日
-    high_over_25: usize,
-    // 熱帯夜
-    low_over_25: usize,
-    // 真冬日
-    high_below_0: usize,
-    // 冬日
-    low_below_0: usize,
+    // 猛暑日・真夏日・夏日・熱帯夜・真冬日・冬日など（ThresholdRules で決める）
+    thresholds: ThresholdCounts,
+    elements: ElementAggregateMap,
+}
+
+// スナップショットから読み戻すときの形（規則ごとの日数は規則名をキーにして並んでいる）
+// 規則名以外のキーはすべてここに書き、残りを規則ごとの日数として読む
+#[derive(Deserialize)]
+struct MonthAggregateFields {
+    min: f64,
+    max: f64,
+    sum: f64,
+    count: usize,
+    elements: ElementAggregateMap,
+    // 書き出すときに付ける平均は読み捨てる
+    #[serde(rename = "average")]
+    _average: Option<IgnoredAny>,
+    #[serde(flatten)]
+    thresholds: ThresholdCounts,
+}
+
+impl From<MonthAggregateFields> for MonthAggregateResult {
+    fn from(value: MonthAggregateFields) -> Self {
+        Self {
+            min: value.min,
+            max: value.max,
+            sum: value.sum,
+            count: value.count,
+            thresholds: value.thresholds,
+            elements: value.elements,
+        }
+    }
 }
 
 impl MonthAggregateResult {
-    fn new() -> Self {
+    fn new(rules: &ThresholdRules) -> Self {
         Self {
             min: f64::MAX,
             max: f64::MIN,
             sum: 0.0,
             count: 0,
-            high_over_35: 0,
-            high_over_30: 0,
-            high_over_25: 0,
-            low_over_25: 0,
-            high_below_0: 0,
-            low_below_0: 0,
+            thresholds: rules.new_counts(),
+            elements: ElementAggregateMap::new(),
         }
     }
 
-    fn add(&mut self, min: f64, max: f64, avg: f64) {
-        self.min = self.min.min(min);
-        self.max = self.max.max(max);
-        self.sum += avg;
-        self.count += 1;
-        if max >= 35.0 {
-            self.high_over_35 += 1;
-        } else if max >= 30.0 {
-            self.high_over_30 += 1;
-        } else if max >= 25.0 {
-            self.high_over_25 += 1;
+    // 欠測のフィールドはそのフィールドに関わる集計だけを飛ばす
+    fn add(&mut self, data: &ObservationPointData, rules: &ThresholdRules) {
+        let (min, max, avg) = (data.min(), data.max(), data.average());
+        add_elements(&mut self.elements, data);
+        if let Some(min) = min {
+            self.min = self.min.min(min);
         }
-        if min >= 25.0 {
-            self.low_over_25 += 1;
+        if let Some(max) = max {
+            self.max = self.max.max(max);
         }
-        if max < 0.0 {
-            self.high_below_0 += 1;
-        } else if min < 0.0 {
-            self.low_below_0 += 1;
+        if let Some(avg) = avg {
+            self.sum += avg;
+            self.count += 1;
         }
+        rules.count(data, &mut self.thresholds);
     }
 
     fn average(&self) -> Option<f64> {
@@ -77,23 +148,57 @@ impl Serialize for MonthAggregateResult {
     where
         S: Serializer
     {
-        use serde::ser::SerializeStruct;
-        let mut state = serializer.serialize_struct("MonthAggregateResult", 10)?;
-        state.serialize_field("min", &self.min)?;
-        state.serialize_field("max", &self.max)?;
-        state.serialize_field("count", &self.count)?;
-        state.serialize_field("highOver35", &self.high_over_35)?;
-        state.serialize_field("highOver30", &self.high_over_30)?;
-        state.serialize_field("highOver25", &self.high_over_25)?;
-        state.serialize_field("lowOver25", &self.low_over_25)?;
-        state.serialize_field("highBelow0", &self.high_below_0)?;
-        state.serialize_field("lowBelow0", &self.low_below_0)?;
-        state.serialize_field("average", &self.average())?;
+        use serde::ser::SerializeMap;
+        let mut state = serializer.serialize_map(Some(6 + self.thresholds.len()))?;
+        state.serialize_entry("min", &self.min)?;
+        state.serialize_entry("max", &self.max)?;
+        state.serialize_entry("sum", &self.sum)?;
+        state.serialize_entry("count", &self.count)?;
+        for (name, count) in &self.thresholds {
+            state.serialize_entry(name, count)?;
+        }
+        state.serialize_entry("average", &self.average())?;
+        state.serialize_entry("elements", &self.elements)?;
         state.end()
     }
 }
 
-#[derive(Clone, Debug, Serialize)]
+// 1 年分の集計と、その年の月ごとの集計
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct YearAggregateResult {
+    total: MonthAggregateResult,
+    // 1〜12（データのある月だけ）
+    months: BTreeMap<u32, MonthAggregateResult>,
+}
+
+impl YearAggregateResult {
+    fn new(rules: &ThresholdRules) -> Self {
+        Self { total: MonthAggregateResult::new(rules), months: BTreeMap::new() }
+    }
+
+    fn add(&mut self, date: NaiveDate, data: &ObservationPointData, rules: &ThresholdRules) {
+        self.total.add(data, rules);
+        self.months.entry(date.month())
+            .or_insert_with(|| MonthAggregateResult::new(rules))
+            .add(data, rules);
+    }
+}
+
+// 地点ごと・年ごとの集計
+pub type YearAggregateMap = BTreeMap<u32, BTreeMap<i32, YearAggregateResult>>;
+
+// 地点ごとに送るデータ（通算の集計に今年の集計を加える）
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct PointPayload<'a> {
+    #[serde(flatten)]
+    point: &'a PointAggregateResult,
+    current_year: Option<&'a YearAggregateResult>,
+    // 通算の記録（月ごと・日ごとの記録は /records で返す）
+    records: Option<&'a RecordSet>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 struct PointAggregateResult {
     min: f64,
@@ -101,25 +206,37 @@ struct PointAggregateResult {
     sum: f64,
     count: usize,
     latest_date: [u32; 3],
-    months: [MonthAggregateResult; 12]
+    months: [MonthAggregateResult; 12],
+    elements: ElementAggregateMap,
+    // 猛暑日・真冬日などが続いている日数と、これまでで最も長く続いた日数
+    streaks: ThresholdStreaks,
 }
 
 impl PointAggregateResult {
-    fn new() -> Self {
-        Self { min: f64::MAX, max: f64::MIN, sum: 0.0, count: 0, latest_date: [1900, 1, 1], months: vec![MonthAggregateResult::new(); 12].try_into().unwrap() }
+    fn new(rules: &ThresholdRules) -> Self {
+        Self { min: f64::MAX, max: f64::MIN, sum: 0.0, count: 0, latest_date: [1900, 1, 1], months: std::array::from_fn(|_| MonthAggregateResult::new(rules)), elements: ElementAggregateMap::new(), streaks: rules.new_streaks() }
     }
     
-    fn add(&mut self, date: NaiveDate, min: f64, max: f64, avg: f64) {
-        self.min = self.min.min(min);
-        self.max = self.max.max(max);
-        self.sum += avg;
-        self.count += 1;
+    fn add(&mut self, date: NaiveDate, data: &ObservationPointData, rules: &ThresholdRules) {
+        let (min, max, avg) = (data.min(), data.max(), data.average());
+        add_elements(&mut self.elements, data);
+        if let Some(min) = min {
+            self.min = self.min.min(min);
+        }
+        if let Some(max) = max {
+            self.max = self.max.max(max);
+        }
+        if let Some(avg) = avg {
+            self.sum += avg;
+            self.count += 1;
+        }
         self.latest_date = [date.year().cast_unsigned(), date.month(), date.day()];
-        self.months[date.month0() as usize].add(min, max, avg);
+        self.months[date.month0() as usize].add(data, rules);
+        rules.update_streaks(date, data, &mut self.streaks);
     }
 }
 
-#[derive(Clone, Debug, Serialize)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 struct PrefectureAggregateResult {
     id: u32,
@@ -130,116 +247,404 @@ struct PrefectureAggregateResult {
     sum: f64,
     count: usize,
     latest_date: [u32; 3],
-    // 猛暑日
-    high_over_35: usize,
-    // 真夏日
-    high_over_30: usize,
-    // 夏日
-    high_over_25: usize,
-    // 熱帯夜
-    low_over_25: usize,
-    // 真冬日
-    high_below_0: usize,
-    // 冬日
-    low_below_0: usize,
+    // 猛暑日・真夏日・夏日・熱帯夜・真冬日・冬日など（ThresholdRules で決める）
+    #[serde(flatten)]
+    thresholds: ThresholdCounts,
 }
 
 impl PrefectureAggregateResult {
-    fn new(id: u32, name: String, prefecture_name: String) -> Self {
+    fn new(id: u32, name: String, prefecture_name: String, rules: &ThresholdRules) -> Self {
         Self { id, name, prefecture_name, latest_date: [1900, 1, 1],
             min: f64::MAX, max: f64::MIN, sum: 0.0, count: 0,
-            high_over_35: 0, high_over_30: 0, high_over_25: 0,
-            low_over_25: 0, high_below_0: 0, low_below_0: 0 }
+            thresholds: rules.new_counts() }
     }
 
-    fn add(&mut self, date: NaiveDate, min: f64, max: f64, avg: f64) {
-        self.min = self.min.min(min);
-        self.max = self.max.max(max);
-        self.sum += avg;
-        self.count += 1;
-        if max >= 35.0 {
-            self.high_over_35 += 1;
-        } else if max >= 30.0 {
-            self.high_over_30 += 1;
-        } else if max >= 25.0 {
-            self.high_over_25 += 1;
+    fn add(&mut self, date: NaiveDate, data: &ObservationPointData, rules: &ThresholdRules) {
+        let (min, max, avg) = (data.min(), data.max(), data.average());
+        if let Some(min) = min {
+            self.min = self.min.min(min);
         }
-        if min >= 25.0 {
-            self.low_over_25 += 1;
+        if let Some(max) = max {
+            self.max = self.max.max(max);
         }
-        if max < 0.0 {
-            self.high_below_0 += 1;
-        } else if min < 0.0 {
-            self.low_below_0 += 1;
+        if let Some(avg) = avg {
+            self.sum += avg;
+            self.count += 1;
         }
+        rules.count(data, &mut self.thresholds);
         self.latest_date = [date.year().cast_unsigned(), date.month(), date.day()];
     }
 }
 
+// スナップショットの形式が変わったら上げる
+const SNAPSHOT_VERSION: u32 = 6;
+
+// スナップショットの先頭の版（ほかのフィールドは読まない）
+#[derive(Serialize, Deserialize)]
+struct SnapshotVersion {
+    version: u32,
+}
+
+// 集計の状態をすべて含むスナップショット
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct AggregatorSnapshot {
+    version: u32,
+    // YYYY-MM-DD
+    last_date: Option<String>,
+    // 規則ごとの日数はこの規則で数えたもの
+    thresholds: ThresholdRules,
+    points: BTreeMap<u32, PointAggregateResult>,
+    prefectures: BTreeMap<u32, PrefectureAggregateResult>,
+    window: BTreeMap<u32, PointWindowSnapshot>,
+    years: YearAggregateMap,
+    normals: BTreeMap<u32, BTreeMap<i32, BlockTotals>>,
+    records: RecordTables,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AggregateResults<'a> {
+    latest_date: Option<[u32; 3]>,
+    points: &'a BTreeMap<u32, PointAggregateResult>,
+    prefectures: &'a BTreeMap<u32, PrefectureAggregateResult>,
+    window: Vec<(u32, WindowAggregateResult)>,
+    years: YearAggregateMap,
+    records: RecordTables,
+}
+
+// 集計に加えなかったフレーム
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AggregateError {
+    Decode(DecodeError),
+    // 最後に受け取った日と同じ日付（重複して届いたフレーム）
+    DuplicateDate(NaiveDate),
+    // 復元したスナップショットにすでに含まれている日付
+    Restored(NaiveDate),
+}
+
+impl fmt::Display for AggregateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AggregateError::Decode(err) => err.fmt(f),
+            AggregateError::DuplicateDate(date) => write!(f, "duplicate frame for {date}"),
+            AggregateError::Restored(date) => write!(f, "frame for {date} is already in the restored snapshot"),
+        }
+    }
+}
+
+impl std::error::Error for AggregateError {}
+
+impl From<DecodeError> for AggregateError {
+    fn from(err: DecodeError) -> Self {
+        AggregateError::Decode(err)
+    }
+}
+
 /*
 地点名を選んで、月（暦月）ごとの統計を表示。（最低、最高、平均、夏日や熱帯夜など）
 
 日較差でグラフ？
  */
-pub(crate) struct Aggregator {
+pub struct Aggregator {
     state: Arc<AppState>,
     aggregate_by_point: BTreeMap<u32, PointAggregateResult>,
     aggregate_by_prefecture: BTreeMap<u32, PrefectureAggregateResult>,
-    window_aggregator: WindowAggregator
+    window_aggregator: WindowAggregator,
+    normal_aggregator: NormalAggregator,
+    // 最後に受け取った日の平年差
+    anomalies: Vec<(u32, Anomaly)>,
+    // 最後に受け取った日に塗り替えた記録
+    new_records: Vec<NewRecord>,
+    // 集計から除外する品質
+    exclude_quality: Vec<Quality>,
+    // 最後に受け取った日付
+    last_date: Option<NaiveDate>,
+    // スナップショットから復元した最後の日付（それより新しい日を受け取るまで持つ）
+    restored_until: Option<NaiveDate>,
+    // 知らない地点のため捨てた記録数
+    unknown_records: usize,
 }
 
 impl Aggregator {
-    pub fn new(state: Arc<AppState>) -> Self {
+    pub fn new(state: Arc<AppState>, exclude_quality: Vec<Quality>) -> Self {
         Self {
             aggregate_by_prefecture: state.observation_points.iter()
                 .filter(|&p| p.is_prefecture_center())
-                .map(|p| (get_prefecture_code(p.prefecture()), PrefectureAggregateResult::new(p.id(), p.name().to_string(), p.prefecture().to_string())))
+                .map(|p| (get_prefecture_code(p.prefecture()), PrefectureAggregateResult::new(p.id(), p.name().to_string(), p.prefecture().to_string(), &state.thresholds)))
                 .collect(),
             aggregate_by_point: BTreeMap::new(),
             state,
-            window_aggregator: WindowAggregator::new()
+            window_aggregator: WindowAggregator::new(),
+            normal_aggregator: NormalAggregator::new(),
+            anomalies: Vec::new(),
+            new_records: Vec::new(),
+            exclude_quality,
+            last_date: None,
+            restored_until: None,
+            unknown_records: 0,
         }
     }
 
-    pub fn on_receive_data(&mut self, binary: Bytes) -> Result<(), anyhow::Error>{
-        let (date, data) = decompress_data(&binary);
+    pub fn last_date(&self) -> Option<NaiveDate> {
+        self.last_date
+    }
+
+    pub fn unknown_records(&self) -> usize {
+        self.unknown_records
+    }
+
+    // ここまでの集計を復元したものとして扱い、最後の日付までのフレームは飛ばす
+    pub fn mark_restored(&mut self) {
+        self.restored_until = self.last_date;
+    }
+
+    // 集計をすべて捨てて最初からやり直す
+    pub fn reset(&mut self) {
+        self.state.years.write().unwrap().clear();
+        self.state.records.write().unwrap().clear();
+        *self = Self::new(self.state.clone(), std::mem::take(&mut self.exclude_quality));
+    }
+
+    // フレームを展開して集計に加え、その日付を返す
+    pub fn aggregate(&mut self, binary: &[u8]) -> Result<NaiveDate, AggregateError> {
+        let (date, data) = decompress_data(binary)?;
+        self.add(date, data)?;
+        Ok(date)
+    }
+
+    pub fn add(&mut self, date: NaiveDate, mut data: Vec<ObservationPointData>) -> Result<(), AggregateError> {
+        // 復元した直後に古い日付から送られてきても集計を捨てず、追いつくまで飛ばす
+        if self.restored_until.is_some_and(|x| date <= x) {
+            return Err(AggregateError::Restored(date));
+        }
+        self.restored_until = None;
+        // 同じ日を 2 回数えないよう、重複したフレームは飛ばす
+        if self.last_date == Some(date) {
+            return Err(AggregateError::DuplicateDate(date));
+        }
+        // 日付が戻ったら再生側で過去にシークされたとみなす
+        if self.last_date.is_some_and(|x| date < x) {
+            println!("Date went back from {} to {date}, resetting aggregates", self.last_date.unwrap());
+            self.reset();
+        }
+        self.last_date = Some(date);
+        // 知らない地点の記録だけを捨て、残りは集計する
+        data.retain(|x| {
+            let known = self.state.observation_point_map.contains_key(&x.point_id());
+            if !known {
+                self.unknown_records += 1;
+                println!("Skipped record ({} so far): {}", self.unknown_records, DecodeError::UnknownStation(x.point_id()));
+            }
+            known
+        });
+        for point_data in &mut data {
+            point_data.exclude_quality(&self.exclude_quality);
+        }
         self.window_aggregator.add(&data);
+        self.anomalies = self.normal_aggregator.anomalies(date, &data);
+        self.normal_aggregator.add(date, &data);
+        let mut years = self.state.years.write().unwrap();
+        let mut records = self.state.records.write().unwrap();
+        self.new_records.clear();
         for point_data in data {
-            if !self.aggregate_by_point.contains_key(&point_data.point_id()) {
-                self.aggregate_by_point.insert(
-                    point_data.point_id(),
-                    PointAggregateResult::new()
-                );
-            }
-            self.aggregate_by_point.get_mut(&point_data.point_id()).unwrap().add(
-                date, point_data.min(), point_data.max(), point_data.average()
-            );
+            self.aggregate_by_point.entry(point_data.point_id())
+                .or_insert_with(|| PointAggregateResult::new(&self.state.thresholds))
+                .add(date, &point_data, &self.state.thresholds);
+            years.entry(point_data.point_id()).or_default()
+                .entry(date.year())
+                .or_insert_with(|| YearAggregateResult::new(&self.state.thresholds))
+                .add(date, &point_data, &self.state.thresholds);
+            records.add(date, &point_data, &mut self.new_records);
 
             let point = self.state.observation_point_map.get(&point_data.point_id()).unwrap();
             if point.is_prefecture_center() {
                 let pref = get_prefecture_code(point.prefecture());
-                self.aggregate_by_prefecture.get_mut(&pref).map(|x|
-                    x.add(
-                        date, point_data.min(), point_data.max(), point_data.average(),
-                    ));
+                if let Some(x) = self.aggregate_by_prefecture.get_mut(&pref) {
+                    x.add(date, &point_data, &self.state.thresholds);
+                }
             }
         }
+        Ok(())
+    }
 
-        self.state.get_tx(0).send(vec![(0, binary)])?;
-        self.state.get_tx(1).send(
+    // 受け取る側がいないチャンネルのデータは作らない（ほかのチャンネルには送る）
+    fn send(&self, index: usize, f: impl FnOnce() -> Result<Vec<(u32, Bytes)>, anyhow::Error>) -> Result<(), anyhow::Error> {
+        let tx = self.state.get_tx(index);
+        if tx.receiver_count() > 0 {
+            // 送るまでに全員が切断したときだけ失敗する
+            let _ = tx.send(f()?);
+        }
+        Ok(())
+    }
+
+    fn broadcast(&self, date: NaiveDate, binary: Bytes) -> Result<(), anyhow::Error> {
+        self.send(0, || Ok(vec![(0, binary)]))?;
+        self.send(1, || {
+            let years = self.state.years.read().unwrap();
+            let records = self.state.records.read().unwrap();
             self.aggregate_by_point
                 .iter()
-                .map(|(id, point)|
-                    (*id, rmp_serde::to_vec_named(point).unwrap().into()))
+                .map(|(id, point)| {
+                    let current_year = years.get(id).and_then(|x| x.get(&date.year()));
+                    let records = records.get(*id).map(|x| x.all_time());
+                    Ok((*id, rmp_serde::to_vec_named(&PointPayload { point, current_year, records })?.into()))
+                })
                 .collect()
-        )?;
-        let bytes = rmp_serde::to_vec_named(&self.aggregate_by_prefecture)?;
-        self.state.get_tx(2).send(vec![(0, bytes.into())])?;
-        let bytes = rmp_serde::to_vec(&(
-            [date.year().cast_unsigned(), date.month(), date.day()],
-            self.window_aggregator.to_vec()
-        ))?;
-        self.state.get_tx(3).send(vec![(0, bytes.into())])?;
+        })?;
+        self.send(2, || Ok(vec![(0, rmp_serde::to_vec_named(&self.aggregate_by_prefecture)?.into())]))?;
+        self.send(3, || {
+            let bytes = rmp_serde::to_vec(&(
+                [date.year().cast_unsigned(), date.month(), date.day()],
+                self.window_aggregator.to_vec()
+            ))?;
+            Ok(vec![(0, bytes.into())])
+        })?;
+        self.send(4, || Ok(vec![(0, rmp_serde::to_vec_named(&AnomalyResults::new(date, &self.anomalies))?.into())]))?;
+        // 記録を塗り替えた日だけ、1 件ずつ送る
+        if self.new_records.is_empty() {
+            return Ok(());
+        }
+        self.send(5, || self.new_records.iter()
+            .map(|x| Ok((x.point_id(), rmp_serde::to_vec_named(x)?.into())))
+            .collect())
+    }
+
+    pub fn on_receive_data(&mut self, binary: Bytes) -> Result<(), anyhow::Error>{
+        let date = self.aggregate(&binary)?;
+        self.broadcast(date, binary)
+    }
+
+    // 集計の状態をすべて書き出す
+    pub fn snapshot(&self) -> Result<Vec<u8>, anyhow::Error> {
+        let snapshot = AggregatorSnapshot {
+            version: SNAPSHOT_VERSION,
+            last_date: self.last_date.map(|x| x.to_string()),
+            thresholds: self.state.thresholds.clone(),
+            points: self.aggregate_by_point.clone(),
+            prefectures: self.aggregate_by_prefecture.clone(),
+            window: self.window_aggregator.snapshot(),
+            years: self.state.years.read().unwrap().clone(),
+            normals: self.normal_aggregator.snapshot(),
+            records: self.state.records.read().unwrap().clone(),
+        };
+        Ok(rmp_serde::to_vec_named(&snapshot)?)
+    }
+
+    // snapshot() で書き出した状態に戻す
+    pub fn restore(&mut self, bytes: &[u8]) -> Result<(), anyhow::Error> {
+        // 形式の違う古いスナップショットを中身の読み込みエラーにしないよう、先に版だけを読む
+        let SnapshotVersion { version } = rmp_serde::from_slice(bytes)?;
+        anyhow::ensure!(version == SNAPSHOT_VERSION, "unsupported snapshot version: {version}");
+        let snapshot: AggregatorSnapshot = rmp_serde::from_slice(bytes)?;
+        // 規則が変わっていると、日数を別の規則のものとして読んでしまう
+        anyhow::ensure!(snapshot.thresholds == self.state.thresholds, "threshold rules differ from the ones in the snapshot");
+        // 途中で失敗しても今の集計が半端に置き換わらないよう、すべて読めてから入れ替える
+        let last_date = snapshot.last_date.map(|x| x.parse()).transpose()?;
+        let window_aggregator = WindowAggregator::restore(snapshot.window)?;
+        let normal_aggregator = NormalAggregator::restore(snapshot.normals)?;
+        snapshot.records.validate()?;
+        self.last_date = last_date;
+        self.restored_until = last_date;
+        self.aggregate_by_point = snapshot.points;
+        self.aggregate_by_prefecture = snapshot.prefectures;
+        self.window_aggregator = window_aggregator;
+        self.normal_aggregator = normal_aggregator;
+        *self.state.years.write().unwrap() = snapshot.years;
+        *self.state.records.write().unwrap() = snapshot.records;
         Ok(())
     }
+
+    // 今の集計結果をまとめて返す（一括集計の出力用）
+    pub fn results(&self) -> AggregateResults<'_> {
+        AggregateResults {
+            latest_date: self.last_date.map(|x| [x.year().cast_unsigned(), x.month(), x.day()]),
+            points: &self.aggregate_by_point,
+            prefectures: &self.aggregate_by_prefecture,
+            window: self.window_aggregator.to_vec(),
+            years: self.state.years.read().unwrap().clone(),
+            records: self.state.records.read().unwrap().clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use server::observation_points::load_observation_points;
+
+    fn new_aggregator() -> Aggregator {
+        let points = load_observation_points("server/data/observation.csv").unwrap();
+        Aggregator::new(Arc::new(AppState::new(points, ThresholdRules::default())), Vec::new())
+    }
+
+    fn date(day: u32) -> NaiveDate {
+        NaiveDate::from_ymd_opt(2020, 8, day).unwrap()
+    }
+
+    fn day(max: f64) -> Vec<ObservationPointData> {
+        let mut point = ObservationPointData::new(40046);
+        point.set_value(Element::AverageTemperature, Some(max - 5.0));
+        point.set_value(Element::MaxTemperature, Some(max));
+        point.set_value(Element::MinTemperature, Some(max - 10.0));
+        vec![point]
+    }
+
+    #[test]
+    fn snapshot_round_trip() {
+        let mut aggregator = new_aggregator();
+        for (i, max) in [30.0, 35.5, 24.9].into_iter().enumerate() {
+            aggregator.add(date(i as u32 + 1), day(max)).unwrap();
+        }
+        let bytes = aggregator.snapshot().unwrap();
+
+        let mut restored = new_aggregator();
+        restored.restore(&bytes).unwrap();
+        assert_eq!(restored.last_date(), Some(date(3)));
+        assert_eq!(restored.snapshot().unwrap(), bytes);
+        // 復元した日付までは飛ばし、その翌日から集計する
+        assert_eq!(restored.add(date(2), day(20.0)).unwrap_err(), AggregateError::Restored(date(2)));
+        restored.add(date(4), day(20.0)).unwrap();
+        aggregator.add(date(4), day(20.0)).unwrap();
+        assert_eq!(restored.snapshot().unwrap(), aggregator.snapshot().unwrap());
+    }
+
+    #[test]
+    fn skips_only_unknown_station_records() {
+        let mut aggregator = new_aggregator();
+        let mut data = day(36.0);
+        data.push(ObservationPointData::new(99999));
+        aggregator.add(date(1), data).unwrap();
+        assert_eq!(aggregator.unknown_records(), 1);
+        assert_eq!(aggregator.last_date(), Some(date(1)));
+        assert!(aggregator.aggregate_by_point.contains_key(&40046));
+        assert!(!aggregator.aggregate_by_point.contains_key(&99999));
+    }
+
+    #[test]
+    fn rejects_stale_snapshot_version() {
+        #[derive(Serialize)]
+        #[serde(rename_all = "camelCase")]
+        struct OldSnapshot {
+            version: u32,
+            last_date: Option<String>,
+        }
+        let bytes = rmp_serde::to_vec_named(&OldSnapshot { version: SNAPSHOT_VERSION - 1, last_date: Some(date(1).to_string()) }).unwrap();
+        let mut aggregator = new_aggregator();
+        let err = aggregator.restore(&bytes).unwrap_err();
+        assert_eq!(err.to_string(), format!("unsupported snapshot version: {}", SNAPSHOT_VERSION - 1));
+        assert_eq!(aggregator.last_date(), None);
+    }
+
+    #[test]
+    fn rejects_snapshot_with_other_rules() {
+        let mut aggregator = new_aggregator();
+        aggregator.add(date(1), day(30.0)).unwrap();
+        let bytes = aggregator.snapshot().unwrap();
+        let points = load_observation_points("server/data/observation.csv").unwrap();
+        let rules = serde_json::from_str(r#"[{"name":"hot","element":"maxTemperature","comparison":">=","value":30}]"#).unwrap();
+        let mut other = Aggregator::new(Arc::new(AppState::new(points, rules)), Vec::new());
+        assert!(other.restore(&bytes).is_err());
+        assert_eq!(other.last_date(), None);
+    }
 }
@@ -0,0 +1,148 @@
+// 記録したフレームのログか日別値 CSV のディレクトリを、待たずに一気に集計して結果を書き出す
+//
+// Usage: reaggregate (--log <DIR> | --csv <DIR>) [--observation <FILE>] [--output <FILE>] [--format json|msgpack] [--exclude-quality <LIST>] [--thresholds <FILE>]
+
+use realtime_data_final::aggregator::Aggregator;
+use realtime_data_final::recorder::read_records;
+use realtime_data_final::threshold::ThresholdRules;
+use realtime_data_final::AppState;
+use server::observation_points::load_observation_points;
+use server::quality::Quality;
+use server::station_reader::CsvSource;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Instant;
+
+const USAGE: &str = "\
+Usage: reaggregate (--log <DIR> | --csv <DIR>) [OPTIONS]
+
+Options:
+  --log <DIR>                ブリッジの --record で記録したディレクトリを読む
+  --csv <DIR>                observation.csv と地点ごとの CSV を置いたディレクトリを読む
+  --observation <FILE>       観測地点の一覧 [default: --csv なら <DIR>/observation.csv、それ以外は ./server/data/observation.csv]
+  --output <FILE>            書き出し先 [default: 標準出力]
+  --format <FORMAT>          json または msgpack [default: json]
+  --exclude-quality <LIST>   集計から除外する品質
+  --thresholds <FILE>        猛暑日などを数える規則の JSON [default: 気象庁の定義]
+  -h, --help                 このヘルプを表示する";
+
+enum Input {
+    Log(PathBuf),
+    Csv(PathBuf),
+}
+
+#[derive(PartialEq)]
+enum Format {
+    Json,
+    MessagePack,
+}
+
+struct Args {
+    input: Input,
+    observation: PathBuf,
+    output: Option<PathBuf>,
+    format: Format,
+    exclude_quality: Vec<Quality>,
+    thresholds: ThresholdRules,
+}
+
+impl Args {
+    fn parse() -> anyhow::Result<Self> {
+        let mut input = None;
+        let mut observation = None;
+        let mut output = None;
+        let mut format = Format::Json;
+        let mut exclude_quality = Vec::new();
+        let mut thresholds = ThresholdRules::default();
+        let mut args = std::env::args().skip(1);
+        while let Some(arg) = args.next() {
+            let mut value = || args.next().ok_or_else(|| anyhow::anyhow!("missing value for {arg}"));
+            match arg.as_str() {
+                "--log" => input = Some(Input::Log(value()?.into())),
+                "--csv" => input = Some(Input::Csv(value()?.into())),
+                "--observation" => observation = Some(value()?.into()),
+                "--output" => output = Some(value()?.into()),
+                "--format" => {
+                    format = match value()?.as_str() {
+                        "json" => Format::Json,
+                        "msgpack" => Format::MessagePack,
+                        other => anyhow::bail!("unknown format: {other}"),
+                    }
+                }
+                "--exclude-quality" => {
+                    exclude_quality = value()?.split(',').map(str::parse).collect::<Result<_, _>>()?;
+                }
+                "--thresholds" => thresholds = ThresholdRules::load(value()?.as_ref())?,
+                "-h" | "--help" => {
+                    println!("{USAGE}");
+                    std::process::exit(0);
+                }
+                _ => anyhow::bail!("unknown option: {arg}\n\n{USAGE}"),
+            }
+        }
+        let input = input.ok_or_else(|| anyhow::anyhow!("--log or --csv is required\n\n{USAGE}"))?;
+        let observation = observation.unwrap_or_else(|| match &input {
+            Input::Csv(dir) => dir.join("observation.csv"),
+            Input::Log(_) => PathBuf::from("./server/data/observation.csv"),
+        });
+        Ok(Self { input, observation, output, format, exclude_quality, thresholds })
+    }
+}
+
+fn main() -> anyhow::Result<()> {
+    let args = Args::parse()?;
+    let points = load_observation_points(&args.observation)?;
+    let state = Arc::new(AppState::new(points, args.thresholds));
+    let mut aggregator = Aggregator::new(state.clone(), args.exclude_quality);
+
+    let start = Instant::now();
+    let mut days = 0usize;
+    let mut skipped_frames = 0usize;
+    let mut unreadable_frames = 0usize;
+    match &args.input {
+        Input::Log(dir) => {
+            for record in read_records(dir)? {
+                // 壊れた記録があっても、残りの集計は続ける
+                let record = match record {
+                    Ok(x) => x,
+                    Err(err) => {
+                        eprintln!("Failed to read recorded frame: {err}");
+                        unreadable_frames += 1;
+                        continue;
+                    }
+                };
+                match aggregator.aggregate(&record.frame) {
+                    Ok(_) => days += 1,
+                    Err(_) => skipped_frames += 1,
+                }
+            }
+        }
+        Input::Csv(dir) => {
+            for day in CsvSource::open(state.observation_points.iter(), dir, |_| true)? {
+                let (date, rows) = day?;
+                if rows.is_empty() { continue; }
+                aggregator.add(date, rows)?;
+                days += 1;
+            }
+        }
+    }
+    eprintln!(
+        "Aggregated {days} day(s) in {} ms, skipped {skipped_frames} frame(s), failed to read {unreadable_frames} frame(s), skipped {} record(s) from unknown stations",
+        start.elapsed().as_millis(),
+        aggregator.unknown_records()
+    );
+
+    let mut writer: BufWriter<Box<dyn Write>> = BufWriter::new(match &args.output {
+        Some(path) => Box::new(File::create(path)?),
+        None => Box::new(std::io::stdout().lock()),
+    });
+    let results = aggregator.results();
+    match args.format {
+        Format::Json => serde_json::to_writer(&mut writer, &results)?,
+        Format::MessagePack => rmp_serde::encode::write_named(&mut writer, &results)?,
+    }
+    writer.flush()?;
+    Ok(())
+}
@@ -0,0 +1,65 @@
+use realtime_data_final::recorder::DEFAULT_SEGMENT_SIZE;
+use realtime_data_final::snapshot::DEFAULT_SNAPSHOT_INTERVAL;
+use server::quality::Quality;
+use std::path::PathBuf;
+use std::time::Duration;
+
+pub(crate) struct Config {
+    // 集計から除外する品質（例: --exclude-quality suspect,insufficient）
+    pub exclude_quality: Vec<Quality>,
+    // 再接続したとき、最後に受け取った日付の翌日から送るよう再生サーバーに頼む（--snapshot, --rebuild でも有効になる）
+    pub resume: bool,
+    // 受け取ったフレームを記録するディレクトリ
+    pub record: Option<PathBuf>,
+    pub record_segment_size: u64,
+    // 起動時に記録を読み直して集計を復元する
+    pub rebuild: bool,
+    // 集計の状態を書き出すファイル（起動時にあれば読み込む）
+    pub snapshot: Option<PathBuf>,
+    pub snapshot_interval: Duration,
+    // 猛暑日などを数える規則の JSON（なければ気象庁の定義）
+    pub thresholds: Option<PathBuf>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            exclude_quality: Vec::new(),
+            resume: false,
+            record: None,
+            record_segment_size: DEFAULT_SEGMENT_SIZE,
+            rebuild: false,
+            snapshot: None,
+            snapshot_interval: DEFAULT_SNAPSHOT_INTERVAL,
+            thresholds: None,
+        }
+    }
+}
+
+impl Config {
+    pub fn from_args() -> anyhow::Result<Self> {
+        let mut config = Self::default();
+        let mut args = std::env::args().skip(1);
+        while let Some(arg) = args.next() {
+            let mut value = || args.next().ok_or_else(|| anyhow::anyhow!("missing value for {arg}"));
+            match arg.as_str() {
+                "--exclude-quality" => {
+                    config.exclude_quality = value()?.split(',').map(str::parse).collect::<Result<_, _>>()?;
+                }
+                "--resume" => config.resume = true,
+                "--record" => config.record = Some(PathBuf::from(value()?)),
+                "--record-segment-size" => config.record_segment_size = value()?.parse()?,
+                "--rebuild" => config.rebuild = true,
+                "--snapshot" => config.snapshot = Some(PathBuf::from(value()?)),
+                // 秒
+                "--snapshot-interval" => config.snapshot_interval = Duration::from_secs(value()?.parse()?),
+                "--thresholds" => config.thresholds = Some(PathBuf::from(value()?)),
+                _ => anyhow::bail!("unknown option: {arg}"),
+            }
+        }
+        anyhow::ensure!(!config.rebuild || config.record.is_some(), "--rebuild needs --record");
+        // 復元した集計の続きから送ってもらう
+        config.resume |= config.snapshot.is_some() || config.rebuild;
+        Ok(config)
+    }
+}
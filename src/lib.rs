@@ -0,0 +1,86 @@
+pub mod aggregator;
+pub mod prefecture;
+pub mod record_aggregator;
+pub mod recorder;
+pub mod snapshot;
+pub mod threshold;
+mod normal_aggregator;
+mod window_aggregator;
+
+use bytes::Bytes;
+use serde::Serialize;
+use crate::aggregator::YearAggregateMap;
+use crate::record_aggregator::RecordTables;
+use crate::threshold::ThresholdRules;
+use server::observation_points::ObservationPoint;
+use std::collections::BTreeMap;
+use std::sync::{Arc, RwLock};
+use tokio::sync::{
+    broadcast,
+    broadcast::Sender,
+};
+
+// 再生サーバーとの接続状態（/status で返す）
+#[derive(Clone, Debug, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UpstreamStatus {
+    pub connected: bool,
+    // 接続に成功した回数
+    pub connections: usize,
+    // 続けて接続に失敗している回数（フレームを受け取れたら 0 に戻す）
+    pub failed_attempts: usize,
+    pub last_error: Option<String>,
+    pub last_date: Option<[u32; 3]>,
+}
+
+pub struct AppState {
+    pub observation_points: Arc<Vec<ObservationPoint>>,
+    pub observation_point_map: Arc<BTreeMap<u32, ObservationPoint>>,
+    pub tx2: Sender<Vec<(u32, Bytes)>>,
+    pub tx3: Sender<Vec<(u32, Bytes)>>,
+    pub tx4: Sender<Vec<(u32, Bytes)>>,
+    pub tx5: Sender<Vec<(u32, Bytes)>>,
+    // 平年差
+    pub tx6: Sender<Vec<(u32, Bytes)>>,
+    // 記録の更新
+    pub tx7: Sender<Vec<(u32, Bytes)>>,
+    pub upstream_status: RwLock<UpstreamStatus>,
+    // 猛暑日などを数える規則
+    pub thresholds: ThresholdRules,
+    // 地点ごと・年ごとの集計（/years で返す）
+    pub years: RwLock<YearAggregateMap>,
+    // 地点ごとの記録（/records で返す）
+    pub records: RwLock<RecordTables>,
+}
+
+impl AppState {
+    pub fn new(points: Vec<ObservationPoint>, thresholds: ThresholdRules) -> Self {
+        let (tx2, _rx) = broadcast::channel(16);
+        let (tx3, _rx) = broadcast::channel(16);
+        let (tx4, _rx) = broadcast::channel(16);
+        let (tx5, _rx) = broadcast::channel(16);
+        let (tx6, _rx) = broadcast::channel(16);
+        let (tx7, _rx) = broadcast::channel(16);
+        Self {
+            observation_point_map: Arc::new(points.iter().map(|x| (x.id(), x.clone())).collect()),
+            observation_points: Arc::new(points),
+            tx2, tx3, tx4, tx5, tx6, tx7,
+            upstream_status: RwLock::new(UpstreamStatus::default()),
+            thresholds,
+            years: RwLock::new(YearAggregateMap::new()),
+            records: RwLock::new(RecordTables::default()),
+        }
+    }
+
+    pub fn get_tx(&self, i: usize) -> &Sender<Vec<(u32, Bytes)>> {
+        match i {
+            0 => &self.tx2,
+            1 => &self.tx3,
+            2 => &self.tx4,
+            3 => &self.tx5,
+            4 => &self.tx6,
+            5 => &self.tx7,
+            _ => panic!("Invalid index"),
+        }
+    }
+}
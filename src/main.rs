@@ -1,12 +1,21 @@
 mod ws;
-mod aggregator;
-mod prefecture;
-mod window_aggregator;
+mod config;
+mod upstream;
 
 use tower_http::cors::CorsLayer;
 use crate::{
     ws::make_websocket_handler,
-    aggregator::Aggregator,
+    config::Config,
+};
+use realtime_data_final::{
+    aggregator::{AggregateError, Aggregator, YearAggregateResult},
+    prefecture::{get_prefecture_code, get_prefectures},
+    record_aggregator::RecordTable,
+    recorder::{find_record, read_records, read_records_from, Recorder},
+    snapshot::Snapshotter,
+    threshold::{ThresholdRule, ThresholdRules},
+    AppState,
+    UpstreamStatus,
 };
 use axum::{
     extract::{ws::WebSocketUpgrade, State},
@@ -14,95 +23,94 @@ use axum::{
     Json,
     Router,
 };
-use bytes::Bytes;
 use server::{
     observation_points::{load_observation_points, ObservationPoint},
 };
 use std::{
-    collections::HashMap,
-    net::{Ipv4Addr, SocketAddrV4},
+    collections::{BTreeMap, HashMap},
     sync::Arc,
 };
-use std::collections::BTreeMap;
 use axum::extract::Query;
+use axum::http::StatusCode;
 use serde::Deserialize;
-use tokio::{
-    io::{
-        AsyncReadExt, BufReader,
-    },
-    net::TcpStream,
-    sync::{
-        broadcast,
-        broadcast::Sender,
-    },
-};
-use tokio::time::Instant;
-use crate::prefecture::get_prefectures;
-
-pub(crate) struct AppState {
-    pub observation_points: Arc<Vec<ObservationPoint>>,
-    pub observation_point_map: Arc<BTreeMap<u32, ObservationPoint>>,
-    pub tx2: Sender<Vec<(u32, Bytes)>>,
-    pub tx3: Sender<Vec<(u32, Bytes)>>,
-    pub tx4: Sender<Vec<(u32, Bytes)>>,
-    pub tx5: Sender<Vec<(u32, Bytes)>>,
+
+#[derive(Deserialize)]
+struct Param {
+    id: Option<u32>
 }
 
-impl AppState {
-    pub fn get_tx(&self, i: usize) -> &Sender<Vec<(u32, Bytes)>> {
-        match i {
-            0 => &self.tx2,
-            1 => &self.tx3,
-            2 => &self.tx4,
-            3 => &self.tx5,
-            _ => panic!("Invalid index"),
-        }
-    }
+// どちらか一方を指定する
+#[derive(Deserialize)]
+struct RecordParam {
+    id: Option<u32>,
+    // 都府県の番号（県庁所在地の地点の記録を返す）
+    prefecture: Option<u32>,
 }
 
 #[derive(Deserialize)]
-struct Param {
-    id: Option<u32>
+struct YearParam {
+    id: u32,
+    // なければすべての年
+    year: Option<i32>,
 }
 
 
 #[tokio::main]
 async fn main() {
+    let config = Config::from_args().unwrap();
     let points = load_observation_points("./server/data/observation.csv").unwrap();
-    let (tx2, _rx) = broadcast::channel(16);
-    let (tx3, _rx) = broadcast::channel(16);
-    let (tx4, _rx) = broadcast::channel(16);
-    let (tx5, _rx) = broadcast::channel(16);
-    let state = Arc::new(AppState {
-        observation_point_map: Arc::new(points.iter().map(|x| (x.id(), x.clone())).collect()),
-        observation_points: Arc::new(points),
-        tx2, tx3, tx4, tx5,
-    });
-
-    let cloned_state = state.clone();
-    let socket_task = tokio::spawn(async move {
-        let mut aggregator = Aggregator::new(cloned_state);
-
-        let socket = TcpStream::connect(SocketAddrV4::new(Ipv4Addr::LOCALHOST, server::PORT)).await.unwrap();
-        println!("Connected to server");
-        let mut reader = BufReader::new(socket);
-
-        let mut buffer = [0; 256];
-        let mut data_cnt = 0usize;
-        let mut aggregate_time_total = 0u128;
-        while let Ok(_) = reader.read_exact(&mut buffer[0..3]).await {
-            let len = buffer[0] as usize;
-            let total_size = 3 + 6 * len;
-
-            let _ = reader.read_exact(&mut buffer[3..total_size]).await;
-
-            let start = Instant::now();
-            let _ = aggregator.on_receive_data(Bytes::copy_from_slice(&buffer[..total_size]));
-            data_cnt += 1;
-            aggregate_time_total += start.elapsed().as_micros();
+    let thresholds = match &config.thresholds {
+        Some(path) => ThresholdRules::load(path).unwrap(),
+        None => ThresholdRules::default(),
+    };
+    let state = Arc::new(AppState::new(points, thresholds));
+
+    let mut aggregator = Aggregator::new(state.clone(), config.exclude_quality);
+    let mut snapshotter = config.snapshot.map(|path| Snapshotter::new(path, config.snapshot_interval));
+    if let Some(snapshotter) = &mut snapshotter {
+        match snapshotter.restore(&mut aggregator) {
+            Ok(true) => println!("Restored snapshot, last date: {:?}", aggregator.last_date()),
+            Ok(false) => {}
+            // 読めないスナップショットは使わず、最初から集計する
+            Err(err) => println!("Failed to restore snapshot, starting from scratch: {err}"),
         }
-        println!("Average Process Time: {} μs (Total {} ms)", aggregate_time_total / data_cnt as u128, aggregate_time_total / 1000);
-    });
+    }
+    if config.rebuild {
+        let dir = config.record.as_ref().unwrap();
+        // スナップショットの最後の日の記録が索引にあれば、その次の記録から読む
+        let position = aggregator.last_date().map(|date| find_record(dir, date)).transpose().unwrap().flatten();
+        let records: Box<dyn Iterator<Item = _>> = match position {
+            Some(position) => Box::new(read_records_from(dir, position).unwrap().skip(1)),
+            None => Box::new(read_records(dir).unwrap()),
+        };
+        let mut count = 0usize;
+        let mut received_at = None;
+        for record in records {
+            let record = match record {
+                Ok(x) => x,
+                Err(err) => {
+                    println!("Failed to read recorded frame: {err}");
+                    continue;
+                }
+            };
+            // 索引で読み飛ばせなかったときは、スナップショットに含まれている日付を集計側で飛ばす
+            match aggregator.aggregate(&record.frame) {
+                Err(AggregateError::Restored(_)) => continue,
+                Err(err) => {
+                    println!("Skipped recorded frame: {err}");
+                    continue;
+                }
+                Ok(_) => {}
+            }
+            count += 1;
+            received_at = chrono::DateTime::from_timestamp_millis(record.timestamp as i64);
+        }
+        // 再生サーバーが最初から送ってきても、読み直した集計を捨てない
+        aggregator.mark_restored();
+        println!("Rebuilt from {count} recorded frame(s), last date: {:?}, received at {:?}", aggregator.last_date(), received_at);
+    }
+    let recorder = config.record.map(|dir| Recorder::open(&dir, config.record_segment_size).unwrap());
+    let socket_task = tokio::spawn(upstream::run(state.clone(), aggregator, recorder, snapshotter, config.resume));
 
     let cors = CorsLayer::new().allow_origin([
         "http://localhost:5173".parse().unwrap(),
@@ -111,6 +119,9 @@ async fn main() {
 
     let app = Router::new()
         .route("/meta", get(meta))
+        .route("/status", get(status))
+        .route("/years", get(years))
+        .route("/records", get(records))
         .route(
             "/ws2",
             get(|ws: WebSocketUpgrade, state: State<Arc<AppState>>, query: Query<Param>| {
@@ -135,6 +146,18 @@ async fn main() {
                 make_websocket_handler(3)(ws, state, query)
             }),
         )
+        .route(
+            "/ws6",
+            get(|ws: WebSocketUpgrade, state: State<Arc<AppState>>, query: Query<Param>| {
+                make_websocket_handler(4)(ws, state, query)
+            }),
+        )
+        .route(
+            "/ws7",
+            get(|ws: WebSocketUpgrade, state: State<Arc<AppState>>, query: Query<Param>| {
+                make_websocket_handler(5)(ws, state, query)
+            }),
+        )
         .layer(cors)
         .with_state(state);
 
@@ -156,14 +179,43 @@ async fn main() {
 
 #[derive(serde::Serialize)]
 #[serde(rename_all = "camelCase")]
-struct Meta {
+struct Meta<'a> {
     observation_points: HashMap<u32, ObservationPoint>,
     prefectures: HashMap<u32, String>,
+    thresholds: &'a [ThresholdRule],
 }
 
 async fn meta(State(state): State<Arc<AppState>>) -> Json<serde_json::Value> {
     Json(serde_json::json!(Meta {
         observation_points: state.observation_points.iter().map(|x| (x.id(), x.clone())).collect::<HashMap<u32, ObservationPoint>>(),
-        prefectures: get_prefectures()
+        prefectures: get_prefectures(),
+        thresholds: state.thresholds.rules(),
     }))
 }
+
+// 地点の年ごとの集計（?id=地点番号&year=年）
+async fn years(State(state): State<Arc<AppState>>, Query(param): Query<YearParam>) -> Result<Json<BTreeMap<i32, YearAggregateResult>>, StatusCode> {
+    let years = state.years.read().unwrap();
+    let point = years.get(&param.id).ok_or(StatusCode::NOT_FOUND)?;
+    Ok(Json(point.iter()
+        .filter(|&(&year, _)| param.year.is_none_or(|x| x == year))
+        .map(|(&year, x)| (year, x.clone()))
+        .collect()))
+}
+
+// 地点の通算・月ごと・日ごとの記録（?id=地点番号 または ?prefecture=都府県の番号）
+async fn records(State(state): State<Arc<AppState>>, Query(param): Query<RecordParam>) -> Result<Json<RecordTable>, StatusCode> {
+    let id = match (param.id, param.prefecture) {
+        (Some(id), None) => id,
+        (None, Some(code)) => state.observation_points.iter()
+            .find(|x| x.is_prefecture_center() && get_prefecture_code(x.prefecture()) == code)
+            .ok_or(StatusCode::NOT_FOUND)?
+            .id(),
+        _ => return Err(StatusCode::BAD_REQUEST),
+    };
+    state.records.read().unwrap().get(id).cloned().map(Json).ok_or(StatusCode::NOT_FOUND)
+}
+
+async fn status(State(state): State<Arc<AppState>>) -> Json<UpstreamStatus> {
+    Json(state.upstream_status.read().unwrap().clone())
+}
@@ -0,0 +1,147 @@
+use chrono::{Datelike, NaiveDate};
+use serde::{Deserialize, Serialize};
+use server::ObservationPointData;
+use std::collections::BTreeMap;
+
+// 平年値を求める年数
+const NORMAL_YEARS: i32 = 30;
+// 2 月 29 日は 2 月 28 日として数える
+const DAYS: usize = 365;
+// 日ごとの平年値は前後この日数を含めて平均する
+const SMOOTHING_DAYS: usize = 7;
+// 期間中にこの割合以上のデータがなければ平年値を出さない
+const MIN_COVERAGE: f64 = 0.8;
+
+// 平均・最高・最低気温
+const TEMPERATURES: usize = 3;
+
+fn temperatures(data: &ObservationPointData) -> [Option<f64>; TEMPERATURES] {
+    [data.average(), data.max(), data.min()]
+}
+
+fn day_index(date: NaiveDate) -> usize {
+    if date.month() == 2 && date.day() == 29 {
+        58
+    } else {
+        NaiveDate::from_ymd_opt(2001, date.month(), date.day()).unwrap().ordinal0() as usize
+    }
+}
+
+// 気象庁と同じく、西暦の 1 の位が 1 の年から 10 年ごとに区切る
+fn block_start(year: i32) -> i32 {
+    (year - 1).div_euclid(10) * 10 + 1
+}
+
+// その年に使う平年値の期間（2021〜2030 年なら 1991〜2020 年）
+pub fn normal_period(year: i32) -> [i32; 2] {
+    let end = block_start(year) - 1;
+    [end - NORMAL_YEARS + 1, end]
+}
+
+// 1 地点・10 年分の、日ごとの合計
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct BlockTotals {
+    sum: Vec<[f64; TEMPERATURES]>,
+    count: Vec<[u32; TEMPERATURES]>,
+}
+
+impl BlockTotals {
+    fn new() -> Self {
+        Self { sum: vec![[0.0; TEMPERATURES]; DAYS], count: vec![[0; TEMPERATURES]; DAYS] }
+    }
+
+    fn add(&mut self, index: usize, values: [Option<f64>; TEMPERATURES]) {
+        for (i, value) in values.into_iter().enumerate() {
+            if let Some(value) = value {
+                self.sum[index][i] += value;
+                self.count[index][i] += 1;
+            }
+        }
+    }
+}
+
+// 観測値 - 平年値
+#[derive(Debug, Serialize)]
+pub struct Anomaly {
+    average: Option<f64>,
+    max: Option<f64>,
+    min: Option<f64>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AnomalyResults<'a> {
+    date: [u32; 3],
+    period: [i32; 2],
+    points: &'a [(u32, Anomaly)],
+}
+
+impl<'a> AnomalyResults<'a> {
+    pub fn new(date: NaiveDate, points: &'a [(u32, Anomaly)]) -> Self {
+        Self { date: [date.year().cast_unsigned(), date.month(), date.day()], period: normal_period(date.year()), points }
+    }
+}
+
+pub struct NormalAggregator {
+    // 地点番号 → 10 年の最初の年 → 合計
+    points: BTreeMap<u32, BTreeMap<i32, BlockTotals>>,
+}
+
+impl NormalAggregator {
+    pub fn new() -> Self {
+        Self { points: BTreeMap::new() }
+    }
+
+    // 平年値の期間より前の 10 年は使わないので捨てる
+    pub fn add(&mut self, date: NaiveDate, data: &[ObservationPointData]) {
+        let [start, _] = normal_period(date.year());
+        for point in data {
+            let blocks = self.points.entry(point.point_id()).or_default();
+            blocks.entry(block_start(date.year()))
+                .or_insert_with(BlockTotals::new)
+                .add(day_index(date), temperatures(point));
+            blocks.retain(|&x, _| x >= start);
+        }
+    }
+
+    fn normal(&self, id: u32, date: NaiveDate) -> [Option<f64>; TEMPERATURES] {
+        let mut sum = [0.0; TEMPERATURES];
+        let mut count = [0u32; TEMPERATURES];
+        let [start, end] = normal_period(date.year());
+        let index = day_index(date);
+        for totals in self.points.get(&id).into_iter().flat_map(|x| x.range(start..=end)).map(|(_, x)| x) {
+            for offset in 0..=SMOOTHING_DAYS * 2 {
+                let i = (index + DAYS + offset - SMOOTHING_DAYS) % DAYS;
+                for field in 0..TEMPERATURES {
+                    sum[field] += totals.sum[i][field];
+                    count[field] += totals.count[i][field];
+                }
+            }
+        }
+        let min_count = (NORMAL_YEARS as usize * (SMOOTHING_DAYS * 2 + 1)) as f64 * MIN_COVERAGE;
+        std::array::from_fn(|i| Some(sum[i] / count[i] as f64).filter(|_| count[i] as f64 >= min_count))
+    }
+
+    // 平年値のある地点だけを返す
+    pub fn anomalies(&self, date: NaiveDate, data: &[ObservationPointData]) -> Vec<(u32, Anomaly)> {
+        data.iter()
+            .filter_map(|point| {
+                let normal = self.normal(point.point_id(), date);
+                let observed = temperatures(point);
+                let [average, max, min] = std::array::from_fn(|i| Some(observed[i]? - normal[i]?));
+                (average.is_some() || max.is_some() || min.is_some())
+                    .then_some((point.point_id(), Anomaly { average, max, min }))
+            })
+            .collect()
+    }
+
+    pub fn snapshot(&self) -> BTreeMap<u32, BTreeMap<i32, BlockTotals>> {
+        self.points.clone()
+    }
+
+    pub fn restore(snapshot: BTreeMap<u32, BTreeMap<i32, BlockTotals>>) -> anyhow::Result<Self> {
+        anyhow::ensure!(snapshot.values().flat_map(|x| x.values()).all(|x| x.sum.len() == DAYS && x.count.len() == DAYS),
+            "normal size mismatch in snapshot");
+        Ok(Self { points: snapshot })
+    }
+}
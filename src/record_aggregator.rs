@@ -0,0 +1,178 @@
+use chrono::{Datelike, NaiveDate};
+use serde::{Deserialize, Serialize};
+use server::ObservationPointData;
+use std::collections::BTreeMap;
+
+// 日ごとの記録は 2 月 29 日も含める
+const DAYS: usize = 366;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum RecordKind {
+    // 最高気温の高い方
+    HighestMax,
+    // 最低気温の低い方
+    LowestMin,
+    // 最低気温の高い方
+    HighestMin,
+    // 最高気温の低い方
+    LowestMax,
+}
+
+impl RecordKind {
+    const ALL: [RecordKind; 4] = [RecordKind::HighestMax, RecordKind::LowestMin, RecordKind::HighestMin, RecordKind::LowestMax];
+
+    fn value(self, data: &ObservationPointData) -> Option<f64> {
+        match self {
+            RecordKind::HighestMax | RecordKind::LowestMax => data.max(),
+            RecordKind::LowestMin | RecordKind::HighestMin => data.min(),
+        }
+    }
+
+    // 同じ値は更新しない（先に記録した日を残す）
+    fn beats(self, value: f64, record: f64) -> bool {
+        match self {
+            RecordKind::HighestMax | RecordKind::HighestMin => value > record,
+            RecordKind::LowestMin | RecordKind::LowestMax => value < record,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum RecordScope {
+    AllTime,
+    // 暦月
+    Month,
+    // 月日
+    Day,
+}
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub struct Record {
+    value: f64,
+    date: [u32; 3],
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RecordSet {
+    highest_max: Option<Record>,
+    lowest_min: Option<Record>,
+    highest_min: Option<Record>,
+    lowest_max: Option<Record>,
+}
+
+impl RecordSet {
+    fn get_mut(&mut self, kind: RecordKind) -> &mut Option<Record> {
+        match kind {
+            RecordKind::HighestMax => &mut self.highest_max,
+            RecordKind::LowestMin => &mut self.lowest_min,
+            RecordKind::HighestMin => &mut self.highest_min,
+            RecordKind::LowestMax => &mut self.lowest_max,
+        }
+    }
+
+    // 記録を塗り替えたら、それまでの記録を返す（初めての値は記録にするだけ）
+    fn update(&mut self, kind: RecordKind, record: Record) -> Option<Record> {
+        let current = self.get_mut(kind);
+        match *current {
+            Some(previous) if kind.beats(record.value, previous.value) => {
+                *current = Some(record);
+                Some(previous)
+            }
+            Some(_) => None,
+            None => {
+                *current = Some(record);
+                None
+            }
+        }
+    }
+}
+
+// 記録を塗り替えたときに送るイベント
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct NewRecord {
+    point_id: u32,
+    kind: RecordKind,
+    scope: RecordScope,
+    #[serde(flatten)]
+    record: Record,
+    previous: Record,
+}
+
+impl NewRecord {
+    pub fn point_id(&self) -> u32 {
+        self.point_id
+    }
+}
+
+// 1 地点の通算・月ごと・日ごとの記録
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RecordTable {
+    all_time: RecordSet,
+    // 1〜12 月
+    months: Vec<RecordSet>,
+    // 1 月 1 日〜12 月 31 日
+    days: Vec<RecordSet>,
+}
+
+impl RecordTable {
+    fn new() -> Self {
+        Self { all_time: RecordSet::default(), months: vec![RecordSet::default(); 12], days: vec![RecordSet::default(); DAYS] }
+    }
+
+    fn add(&mut self, date: NaiveDate, data: &ObservationPointData, events: &mut Vec<NewRecord>) {
+        let day = NaiveDate::from_ymd_opt(2000, date.month(), date.day()).unwrap().ordinal0() as usize;
+        for kind in RecordKind::ALL {
+            let Some(value) = kind.value(data) else { continue };
+            let record = Record { value, date: [date.year().cast_unsigned(), date.month(), date.day()] };
+            for (scope, set) in [
+                (RecordScope::AllTime, &mut self.all_time),
+                (RecordScope::Month, &mut self.months[date.month0() as usize]),
+                (RecordScope::Day, &mut self.days[day]),
+            ] {
+                if let Some(previous) = set.update(kind, record) {
+                    events.push(NewRecord { point_id: data.point_id(), kind, scope, record, previous });
+                }
+            }
+        }
+    }
+
+    pub fn all_time(&self) -> &RecordSet {
+        &self.all_time
+    }
+}
+
+// 地点ごとの記録
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct RecordTables {
+    points: BTreeMap<u32, RecordTable>,
+}
+
+impl RecordTables {
+    // 塗り替えた記録を events に加える
+    pub fn add(&mut self, date: NaiveDate, data: &ObservationPointData, events: &mut Vec<NewRecord>) {
+        self.points.entry(data.point_id())
+            .or_insert_with(RecordTable::new)
+            .add(date, data, events);
+    }
+
+    pub fn get(&self, id: u32) -> Option<&RecordTable> {
+        self.points.get(&id)
+    }
+
+    pub fn clear(&mut self) {
+        self.points.clear();
+    }
+
+    // スナップショットから読んだ表の大きさを確かめる
+    pub fn validate(&self) -> anyhow::Result<()> {
+        anyhow::ensure!(self.points.values().all(|x| x.months.len() == 12 && x.days.len() == DAYS),
+            "record table size mismatch in snapshot");
+        Ok(())
+    }
+}
@@ -0,0 +1,299 @@
+use chrono::NaiveDate;
+use server::frame::{CRC_LEN, HEADER_LEN, MAX_PAYLOAD_LEN};
+use std::fs::{File, OpenOptions};
+use std::io::{BufReader, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// 1 つのセグメントの大きさの既定値
+pub const DEFAULT_SEGMENT_SIZE: u64 = 64 << 20;
+// 日付の索引（日付,セグメント番号,オフセット,受信時刻）
+const INDEX_FILE: &str = "index.csv";
+// 受信時刻 (u64 BE, UNIX ミリ秒) とフレームの長さ (u32 BE)
+const RECORD_HEADER_LEN: usize = 12;
+// これより長いフレームは記録が壊れているとみなす
+const MAX_FRAME_LEN: usize = HEADER_LEN + MAX_PAYLOAD_LEN + CRC_LEN;
+
+fn segment_path(dir: &Path, segment: u32) -> PathBuf {
+    dir.join(format!("segment-{segment:06}.log"))
+}
+
+// dir にあるセグメントの番号を小さい順に
+pub fn segments(dir: &Path) -> anyhow::Result<Vec<u32>> {
+    let mut res = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let name = entry?.file_name();
+        let segment = name.to_str()
+            .and_then(|x| x.strip_prefix("segment-"))
+            .and_then(|x| x.strip_suffix(".log"))
+            .and_then(|x| x.parse().ok());
+        if let Some(segment) = segment {
+            res.push(segment);
+        }
+    }
+    res.sort();
+    Ok(res)
+}
+
+// 受け取ったフレームを受信時刻とともにセグメントファイルに追記していく
+// セグメントが segment_size を超えたら次のファイルに切り替える
+pub struct Recorder {
+    dir: PathBuf,
+    segment_size: u64,
+    segment: u32,
+    file: File,
+    offset: u64,
+    index: File,
+}
+
+impl Recorder {
+    // 既に記録があれば、最後のセグメントの続きから書く
+    // 書きかけで終わっていれば、その記録を切り捨ててから続ける
+    pub fn open(dir: &Path, segment_size: u64) -> anyhow::Result<Self> {
+        std::fs::create_dir_all(dir)?;
+        let segment = segments(dir)?.last().copied().unwrap_or(1);
+        let file = OpenOptions::new().create(true).append(true).open(segment_path(dir, segment))?;
+        let offset = complete_len(&segment_path(dir, segment))?;
+        let len = file.metadata()?.len();
+        if offset < len {
+            println!("Truncating {} byte(s) of incomplete record at the end of segment {segment}", len - offset);
+            file.set_len(offset)?;
+        }
+        let index_path = dir.join(INDEX_FILE);
+        let new_index = !index_path.exists();
+        let mut index = OpenOptions::new().create(true).append(true).open(index_path)?;
+        if new_index {
+            writeln!(index, "date,segment,offset,timestamp")?;
+        }
+        Ok(Self { dir: dir.to_path_buf(), segment_size, segment, file, offset, index })
+    }
+
+    fn rotate(&mut self) -> anyhow::Result<()> {
+        self.segment += 1;
+        self.file = OpenOptions::new().create(true).append(true).open(segment_path(&self.dir, self.segment))?;
+        self.offset = 0;
+        println!("Recording to segment {}", self.segment);
+        Ok(())
+    }
+
+    pub fn append(&mut self, date: NaiveDate, frame: &[u8]) -> anyhow::Result<()> {
+        let len = (RECORD_HEADER_LEN + frame.len()) as u64;
+        if self.offset > 0 && self.offset + len > self.segment_size {
+            self.rotate()?;
+        }
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis() as u64;
+        let mut record = Vec::with_capacity(len as usize);
+        record.extend_from_slice(&timestamp.to_be_bytes());
+        record.extend_from_slice(&u32::try_from(frame.len())?.to_be_bytes());
+        record.extend_from_slice(frame);
+        // 書きかけで失敗したら、次の記録がずれないよう書く前の長さに戻す
+        // 途中で落ちたときは、次に開いたときに書きかけの記録を切り捨てる
+        if let Err(err) = self.file.write_all(&record) {
+            self.file.set_len(self.offset)?;
+            return Err(err.into());
+        }
+        writeln!(self.index, "{date},{},{},{timestamp}", self.segment, self.offset)?;
+        self.offset += len;
+        Ok(())
+    }
+}
+
+// 記録したフレーム
+pub struct RecordedFrame {
+    // UNIX ミリ秒
+    pub timestamp: u64,
+    pub frame: Vec<u8>,
+}
+
+// 記録の位置
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RecordPosition {
+    pub segment: u32,
+    pub offset: u64,
+}
+
+// 1 件読む。セグメントの終わりか書きかけの記録なら None
+// 長さが壊れていれば InvalidData を返す
+fn read_record(reader: &mut impl Read) -> std::io::Result<Option<RecordedFrame>> {
+    let mut header = [0u8; RECORD_HEADER_LEN];
+    match reader.read_exact(&mut header) {
+        Ok(()) => {}
+        Err(err) if err.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+        Err(err) => return Err(err),
+    }
+    let timestamp = u64::from_be_bytes(header[..8].try_into().unwrap());
+    let len = u32::from_be_bytes(header[8..].try_into().unwrap()) as usize;
+    if len > MAX_FRAME_LEN {
+        return Err(std::io::Error::new(ErrorKind::InvalidData, format!("corrupted record: frame length {len}")));
+    }
+    let mut frame = vec![0u8; len];
+    match reader.read_exact(&mut frame) {
+        Ok(()) => Ok(Some(RecordedFrame { timestamp, frame })),
+        Err(err) if err.kind() == ErrorKind::UnexpectedEof => Ok(None),
+        Err(err) => Err(err),
+    }
+}
+
+// セグメントの先頭から、最後まで書けている記録の長さ
+fn complete_len(path: &Path) -> anyhow::Result<u64> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut len = 0;
+    loop {
+        match read_record(&mut reader) {
+            Ok(Some(record)) => len += (RECORD_HEADER_LEN + record.frame.len()) as u64,
+            Ok(None) => return Ok(len),
+            Err(err) if err.kind() == ErrorKind::InvalidData => return Ok(len),
+            Err(err) => return Err(err.into()),
+        }
+    }
+}
+
+// 索引から、date の記録のうち最後に書いたものの位置を探す（索引になければ None）
+pub fn find_record(dir: &Path, date: NaiveDate) -> anyhow::Result<Option<RecordPosition>> {
+    let index = match std::fs::read_to_string(dir.join(INDEX_FILE)) {
+        Ok(x) => x,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err.into()),
+    };
+    let date = date.to_string();
+    Ok(index.lines().skip(1).filter_map(|line| {
+        let mut fields = line.split(',');
+        if fields.next()? != date {
+            return None;
+        }
+        Some(RecordPosition { segment: fields.next()?.parse().ok()?, offset: fields.next()?.parse().ok()? })
+    }).last())
+}
+
+// dir のセグメントを順に読む
+pub fn read_records(dir: &Path) -> anyhow::Result<impl Iterator<Item = anyhow::Result<RecordedFrame>>> {
+    read_records_from(dir, RecordPosition { segment: 0, offset: 0 })
+}
+
+// from の位置の記録から順に読む
+// 書きかけで終わっている最後の記録は読み飛ばし、長さが壊れていればそのセグメントの残りを飛ばす
+pub fn read_records_from(dir: &Path, from: RecordPosition) -> anyhow::Result<impl Iterator<Item = anyhow::Result<RecordedFrame>>> {
+    let dir = dir.to_path_buf();
+    let mut segments = segments(&dir)?.into_iter().filter(move |&x| x >= from.segment);
+    let mut reader: Option<BufReader<File>> = None;
+    Ok(std::iter::from_fn(move || loop {
+        let Some(current) = &mut reader else {
+            let segment = segments.next()?;
+            let offset = if segment == from.segment { from.offset } else { 0 };
+            let file = File::open(segment_path(&dir, segment))
+                .and_then(|mut file| file.seek(SeekFrom::Start(offset)).map(|_| file));
+            match file {
+                Ok(file) => reader = Some(BufReader::new(file)),
+                Err(err) => return Some(Err(err.into())),
+            }
+            continue;
+        };
+        match read_record(current) {
+            Ok(Some(record)) => return Some(Ok(record)),
+            Ok(None) => reader = None,
+            Err(err) => {
+                reader = None;
+                return Some(Err(err.into()));
+            }
+        }
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // テストごとに空のディレクトリを作る
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("recorder-{name}-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn date(day: u32) -> NaiveDate {
+        NaiveDate::from_ymd_opt(2000, 1, day).unwrap()
+    }
+
+    fn frames(dir: &Path) -> Vec<Vec<u8>> {
+        read_records(dir).unwrap().map(|x| x.unwrap().frame).collect()
+    }
+
+    fn record_len(frame_len: usize) -> u64 {
+        (RECORD_HEADER_LEN + frame_len) as u64
+    }
+
+    #[test]
+    fn open_truncates_torn_record() {
+        let dir = test_dir("torn");
+        let mut recorder = Recorder::open(&dir, DEFAULT_SEGMENT_SIZE).unwrap();
+        for day in 1..=3 {
+            recorder.append(date(day), &[day as u8; 10]).unwrap();
+        }
+        drop(recorder);
+        // 長さ 50 のうち 5 バイトしか書けなかった記録
+        let path = segment_path(&dir, 1);
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(&[0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 50, 9, 9, 9, 9, 9]).unwrap();
+        drop(file);
+
+        let mut recorder = Recorder::open(&dir, DEFAULT_SEGMENT_SIZE).unwrap();
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), record_len(10) * 3);
+        recorder.append(date(4), &[4; 10]).unwrap();
+        assert_eq!(frames(&dir), (1..=4).map(|x| vec![x; 10]).collect::<Vec<_>>());
+        assert_eq!(find_record(&dir, date(4)).unwrap(), Some(RecordPosition { segment: 1, offset: record_len(10) * 3 }));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn rejects_oversize_length() {
+        let dir = test_dir("oversize");
+        let mut recorder = Recorder::open(&dir, DEFAULT_SEGMENT_SIZE).unwrap();
+        recorder.append(date(1), &[1; 10]).unwrap();
+        drop(recorder);
+        let path = segment_path(&dir, 1);
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(&0u64.to_be_bytes()).unwrap();
+        file.write_all(&(MAX_FRAME_LEN as u32 + 1).to_be_bytes()).unwrap();
+        drop(file);
+
+        let records = read_records(&dir).unwrap().collect::<Vec<_>>();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].as_ref().unwrap().frame, [1; 10]);
+        assert!(records[1].as_ref().err().unwrap().to_string().contains("frame length"));
+        assert_eq!(complete_len(&path).unwrap(), record_len(10));
+        Recorder::open(&dir, DEFAULT_SEGMENT_SIZE).unwrap();
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), record_len(10));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn find_record_across_segments() {
+        let dir = test_dir("index");
+        // 2 件ごとに次のセグメントに切り替わる
+        let mut recorder = Recorder::open(&dir, record_len(10) * 2).unwrap();
+        // 4 日目の後に 2 日目へ戻ったときは、後から書いた方を探す
+        let days = [1, 2, 3, 4, 2, 3, 5];
+        for (i, day) in days.into_iter().enumerate() {
+            recorder.append(date(day), &[i as u8; 10]).unwrap();
+        }
+        drop(recorder);
+        assert_eq!(segments(&dir).unwrap(), [1, 2, 3, 4]);
+
+        let position = find_record(&dir, date(2)).unwrap().unwrap();
+        assert_eq!(position, RecordPosition { segment: 3, offset: 0 });
+        let rest = read_records_from(&dir, position).unwrap().skip(1).map(|x| x.unwrap().frame[0]).collect::<Vec<_>>();
+        assert_eq!(rest, [5, 6]);
+
+        let position = find_record(&dir, date(3)).unwrap().unwrap();
+        assert_eq!(position, RecordPosition { segment: 3, offset: record_len(10) });
+        let rest = read_records_from(&dir, position).unwrap().skip(1).map(|x| x.unwrap().frame[0]).collect::<Vec<_>>();
+        assert_eq!(rest, [6]);
+
+        let position = find_record(&dir, date(1)).unwrap().unwrap();
+        let rest = read_records_from(&dir, position).unwrap().skip(1).map(|x| x.unwrap().frame[0]).collect::<Vec<_>>();
+        assert_eq!(rest, [1, 2, 3, 4, 5, 6]);
+
+        assert_eq!(find_record(&dir, date(9)).unwrap(), None);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,55 @@
+use crate::aggregator::Aggregator;
+use chrono::NaiveDate;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+// 既定のスナップショットの間隔
+pub const DEFAULT_SNAPSHOT_INTERVAL: Duration = Duration::from_secs(60);
+
+// 集計の状態を一定時間ごとにファイルへ書き出す
+pub struct Snapshotter {
+    path: PathBuf,
+    interval: Duration,
+    last_saved: Instant,
+    // 最後に書き出したときの最終日付（変わっていなければ書き出さない）
+    saved_date: Option<NaiveDate>,
+}
+
+impl Snapshotter {
+    pub fn new(path: PathBuf, interval: Duration) -> Self {
+        Self { path, interval, last_saved: Instant::now(), saved_date: None }
+    }
+
+    // ファイルがあれば読み込んで true を返す
+    pub fn restore(&mut self, aggregator: &mut Aggregator) -> anyhow::Result<bool> {
+        if !self.path.exists() {
+            return Ok(false);
+        }
+        aggregator.restore(&std::fs::read(&self.path)?)?;
+        self.saved_date = aggregator.last_date();
+        Ok(true)
+    }
+
+    // 書きかけのファイルが残らないよう、一時ファイルに書いてから置き換える
+    pub fn save(&mut self, aggregator: &Aggregator) -> anyhow::Result<()> {
+        if aggregator.last_date() == self.saved_date {
+            return Ok(());
+        }
+        let bytes = aggregator.snapshot()?;
+        let mut tmp = self.path.clone().into_os_string();
+        tmp.push(".tmp");
+        std::fs::write(&tmp, bytes)?;
+        std::fs::rename(&tmp, &self.path)?;
+        self.last_saved = Instant::now();
+        self.saved_date = aggregator.last_date();
+        Ok(())
+    }
+
+    // 前回から interval 以上たっていれば書き出す
+    pub fn save_if_due(&mut self, aggregator: &Aggregator) -> anyhow::Result<()> {
+        if self.last_saved.elapsed() >= self.interval {
+            self.save(aggregator)?;
+        }
+        Ok(())
+    }
+}
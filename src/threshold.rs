@@ -0,0 +1,270 @@
+use chrono::{Datelike, NaiveDate};
+use serde::{Deserialize, Serialize};
+use server::element::Element;
+use server::ObservationPointData;
+use std::collections::BTreeMap;
+use std::path::Path;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Comparison {
+    #[serde(rename = ">=")]
+    GreaterOrEqual,
+    #[serde(rename = ">")]
+    Greater,
+    #[serde(rename = "<=")]
+    LessOrEqual,
+    #[serde(rename = "<")]
+    Less,
+}
+
+impl Comparison {
+    fn test(self, value: f64, threshold: f64) -> bool {
+        match self {
+            Comparison::GreaterOrEqual => value >= threshold,
+            Comparison::Greater => value > threshold,
+            Comparison::LessOrEqual => value <= threshold,
+            Comparison::Less => value < threshold,
+        }
+    }
+}
+
+// 「最高気温が 35 ℃以上の日を highOver35 として数える」のような規則
+// 同じ group の規則は上から順に調べ、最初に当てはまったものだけを数える
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ThresholdRule {
+    pub name: String,
+    pub element: Element,
+    pub comparison: Comparison,
+    pub value: f64,
+    #[serde(default)]
+    pub group: Option<String>,
+}
+
+impl ThresholdRule {
+    fn new(name: &str, element: Element, comparison: Comparison, value: f64, group: Option<&str>) -> Self {
+        Self { name: name.to_string(), element, comparison, value, group: group.map(str::to_string) }
+    }
+
+    pub fn matches(&self, data: &ObservationPointData) -> bool {
+        data.get(self.element).is_some_and(|x| self.comparison.test(x, self.value))
+    }
+}
+
+// 規則の名前ごとの日数
+pub type ThresholdCounts = BTreeMap<String, usize>;
+
+// 規則に当てはまる日が続いた期間
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub struct Streak {
+    start: [u32; 3],
+    end: [u32; 3],
+    days: usize,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct StreakResult {
+    // 最後に受け取った日まで続いているもの
+    current: Option<Streak>,
+    // 同じ日数なら先に続いた方
+    longest: Option<Streak>,
+}
+
+impl StreakResult {
+    // データのない日があれば途切れたとみなす
+    fn add(&mut self, date: NaiveDate, matched: bool) {
+        if !matched {
+            self.current = None;
+            return;
+        }
+        let today = [date.year().cast_unsigned(), date.month(), date.day()];
+        let continues = self.current
+            .and_then(|x| NaiveDate::from_ymd_opt(x.end[0] as i32, x.end[1], x.end[2]))
+            .is_some_and(|x| x.succ_opt() == Some(date));
+        let current = match self.current {
+            Some(x) if continues => Streak { end: today, days: x.days + 1, ..x },
+            _ => Streak { start: today, end: today, days: 1 },
+        };
+        self.current = Some(current);
+        if self.longest.is_none_or(|x| current.days > x.days) {
+            self.longest = Some(current);
+        }
+    }
+}
+
+// 規則の名前ごとの連続日数
+pub type ThresholdStreaks = BTreeMap<String, StreakResult>;
+
+// 集計結果で規則ごとの日数と同じ階層に並ぶキー（月ごとの集計と県庁所在地の集計）
+const RESERVED_NAMES: [&str; 10] = ["min", "max", "sum", "count", "average", "elements", "id", "name", "prefectureName", "latestDate"];
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct ThresholdRules {
+    rules: Vec<ThresholdRule>,
+}
+
+impl Default for ThresholdRules {
+    // 気象庁の猛暑日・真夏日・夏日・熱帯夜・真冬日・冬日
+    fn default() -> Self {
+        use Comparison::*;
+        use Element::*;
+        Self {
+            rules: vec![
+                ThresholdRule::new("highOver35", MaxTemperature, GreaterOrEqual, 35.0, Some("high")),
+                ThresholdRule::new("highOver30", MaxTemperature, GreaterOrEqual, 30.0, Some("high")),
+                ThresholdRule::new("highOver25", MaxTemperature, GreaterOrEqual, 25.0, Some("high")),
+                ThresholdRule::new("lowOver25", MinTemperature, GreaterOrEqual, 25.0, None),
+                ThresholdRule::new("highBelow0", MaxTemperature, Less, 0.0, Some("below0")),
+                ThresholdRule::new("lowBelow0", MinTemperature, Less, 0.0, Some("below0")),
+            ],
+        }
+    }
+}
+
+impl ThresholdRules {
+    // [{"name": "highOver38", "element": "maxTemperature", "comparison": ">=", "value": 38, "group": "high"}, ...]
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let rules: Vec<ThresholdRule> = serde_json::from_slice(&std::fs::read(path)?)?;
+        for (i, rule) in rules.iter().enumerate() {
+            anyhow::ensure!(rules[..i].iter().all(|x| x.name != rule.name), "duplicate threshold rule: {}", rule.name);
+            anyhow::ensure!(!RESERVED_NAMES.contains(&rule.name.as_str()), "reserved threshold rule name: {}", rule.name);
+        }
+        Ok(Self { rules })
+    }
+
+    pub fn rules(&self) -> &[ThresholdRule] {
+        &self.rules
+    }
+
+    // すべての規則を 0 日で
+    pub fn new_counts(&self) -> ThresholdCounts {
+        self.rules.iter().map(|x| (x.name.clone(), 0)).collect()
+    }
+
+    pub fn new_streaks(&self) -> ThresholdStreaks {
+        self.rules.iter().map(|x| (x.name.clone(), StreakResult::default())).collect()
+    }
+
+    // 連続日数は group に関係なく数える（猛暑日も真夏日の連続に含める）
+    pub fn update_streaks(&self, date: NaiveDate, data: &ObservationPointData, streaks: &mut ThresholdStreaks) {
+        for rule in &self.rules {
+            streaks.entry(rule.name.clone()).or_default().add(date, rule.matches(data));
+        }
+    }
+
+    pub fn count(&self, data: &ObservationPointData, counts: &mut ThresholdCounts) {
+        let mut matched_groups: Vec<&str> = Vec::new();
+        for rule in &self.rules {
+            let group = rule.group.as_deref();
+            if group.is_some_and(|x| matched_groups.contains(&x)) || !rule.matches(data) {
+                continue;
+            }
+            match counts.get_mut(&rule.name) {
+                Some(count) => *count += 1,
+                None => { counts.insert(rule.name.clone(), 1); }
+            }
+            matched_groups.extend(group);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn point(max: Option<f64>, min: Option<f64>) -> ObservationPointData {
+        let mut res = ObservationPointData::new(40046);
+        res.set_value(Element::MaxTemperature, max);
+        res.set_value(Element::MinTemperature, min);
+        res
+    }
+
+    // 規則にする前の if の連なりで数えた分類
+    fn old_categories(max: Option<f64>, min: Option<f64>) -> Vec<&'static str> {
+        let mut res = Vec::new();
+        if let Some(max) = max {
+            if max >= 35.0 {
+                res.push("highOver35");
+            } else if max >= 30.0 {
+                res.push("highOver30");
+            } else if max >= 25.0 {
+                res.push("highOver25");
+            }
+        }
+        if min.is_some_and(|min| min >= 25.0) {
+            res.push("lowOver25");
+        }
+        if max.is_some_and(|max| max < 0.0) {
+            res.push("highBelow0");
+        } else if min.is_some_and(|min| min < 0.0) {
+            res.push("lowBelow0");
+        }
+        res
+    }
+
+    #[test]
+    fn default_rules_match_old_categories() {
+        let rules = ThresholdRules::default();
+        let values = [None, Some(-0.1), Some(0.0), Some(24.9), Some(25.0), Some(29.9), Some(30.0), Some(34.9), Some(35.0), Some(40.0)];
+        for max in values {
+            for min in values {
+                let mut counts = rules.new_counts();
+                rules.count(&point(max, min), &mut counts);
+                let mut expected = rules.new_counts();
+                for name in old_categories(max, min) {
+                    *expected.get_mut(name).unwrap() += 1;
+                }
+                assert_eq!(counts, expected, "max {max:?}, min {min:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn streaks_break_on_gap_and_missing_value() {
+        let rules = ThresholdRules::default();
+        let mut streaks = rules.new_streaks();
+        let date = |day| NaiveDate::from_ymd_opt(2020, 8, day).unwrap();
+        for (day, max) in [(1, Some(36.0)), (2, Some(35.0)), (3, Some(31.0)), (4, None), (5, Some(35.5)), (7, Some(37.0))] {
+            rules.update_streaks(date(day), &point(max, Some(26.0)), &mut streaks);
+        }
+        let days = |x: Option<Streak>| x.map(|x| x.days);
+        // 猛暑日は 3 日目で途切れる
+        assert_eq!(days(streaks["highOver35"].longest), Some(2));
+        assert_eq!(streaks["highOver35"].current.map(|x| x.start), Some([2020, 8, 7]));
+        // 真夏日は猛暑日も含めて数え、欠測の 4 日目で途切れる
+        assert_eq!(days(streaks["highOver30"].longest), Some(3));
+        // 熱帯夜は 4 日目も続くが、6 日目のデータがないので途切れる
+        assert_eq!(days(streaks["lowOver25"].longest), Some(5));
+        assert_eq!(days(streaks["lowOver25"].current), Some(1));
+        assert_eq!(days(streaks["highBelow0"].longest), None);
+    }
+
+    fn load_str(name: &str, json: &str) -> anyhow::Result<ThresholdRules> {
+        let path = std::env::temp_dir().join(format!("thresholds-{name}-{}.json", std::process::id()));
+        std::fs::write(&path, json).unwrap();
+        let res = ThresholdRules::load(&path);
+        std::fs::remove_file(&path).unwrap();
+        res
+    }
+
+    #[test]
+    fn load_rules() {
+        let rules = load_str("ok", r#"[
+            {"name": "highOver38", "element": "maxTemperature", "comparison": ">=", "value": 38, "group": "high"},
+            {"name": "rainy", "element": "precipitation", "comparison": ">=", "value": 1}
+        ]"#).unwrap();
+        assert_eq!(rules.rules().len(), 2);
+        assert_eq!(rules.rules()[1].group, None);
+    }
+
+    #[test]
+    fn load_rejects_duplicate_and_reserved_names() {
+        let rule = |name: &str| format!(r#"{{"name": "{name}", "element": "maxTemperature", "comparison": ">=", "value": 30}}"#);
+        let err = load_str("dup", &format!("[{}, {}]", rule("hot"), rule("hot"))).unwrap_err();
+        assert_eq!(err.to_string(), "duplicate threshold rule: hot");
+        for name in RESERVED_NAMES {
+            let err = load_str("reserved", &format!("[{}]", rule(name))).unwrap_err();
+            assert_eq!(err.to_string(), format!("reserved threshold rule name: {name}"));
+        }
+    }
+}
@@ -0,0 +1,141 @@
+use bytes::Bytes;
+use chrono::Datelike;
+use realtime_data_final::aggregator::{AggregateError, Aggregator};
+use realtime_data_final::recorder::Recorder;
+use realtime_data_final::snapshot::Snapshotter;
+use realtime_data_final::{AppState, UpstreamStatus};
+use server::scanner::FrameScanner;
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tokio::time::Instant;
+
+// 再接続までの待ち時間（失敗するたびに倍にする）
+const INITIAL_BACKOFF: Duration = Duration::from_millis(500);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+fn update_status(state: &AppState, f: impl FnOnce(&mut UpstreamStatus)) {
+    f(&mut state.upstream_status.write().unwrap());
+}
+
+// 1 回の接続の結果
+struct Session {
+    // 受け取ったフレームの数
+    frames: usize,
+    // 切れた理由（None ならサーバーが閉じた）
+    error: Option<anyhow::Error>,
+}
+
+// 1 回の接続でフレームを受け取り続ける。接続できなかったときだけ Err を返す
+async fn receive(
+    state: &AppState,
+    aggregator: &mut Aggregator,
+    recorder: &mut Option<Recorder>,
+    snapshotter: &mut Option<Snapshotter>,
+    resume: bool,
+) -> anyhow::Result<Session> {
+    let mut socket = TcpStream::connect(SocketAddrV4::new(Ipv4Addr::LOCALHOST, server::PORT)).await?;
+    println!("Connected to server");
+    // 最後に受け取った日付の翌日から送ってもらう
+    if let Some(date) = aggregator.last_date().filter(|_| resume) {
+        println!("Requesting resume after {date}");
+        socket.write_all(format!("resume {date}\n").as_bytes()).await?;
+    }
+    update_status(state, |x| {
+        x.connected = true;
+        x.connections += 1;
+    });
+    let mut reader = BufReader::new(socket);
+
+    let mut scanner = FrameScanner::new();
+    let mut chunk = [0u8; 4096];
+    let mut data_cnt = 0usize;
+    let mut skipped_frame_cnt = 0usize;
+    let mut dropped_frame_cnt = 0usize;
+    let mut aggregate_time_total = 0u128;
+    let error = loop {
+        while let Some(frame) = scanner.next_frame() {
+            let start = Instant::now();
+            let frame = Bytes::from(frame);
+            match aggregator.on_receive_data(frame.clone()) {
+                Err(err) if err.is::<AggregateError>() => {
+                    skipped_frame_cnt += 1;
+                    // 復元したスナップショットに追いつくまでは 1 件ずつ出さない
+                    if !matches!(err.downcast_ref(), Some(AggregateError::Restored(_))) {
+                        println!("Skipped frame ({} so far): {err}", skipped_frame_cnt);
+                    }
+                    continue;
+                }
+                _ => {}
+            }
+            if let (Some(recorder), Some(date)) = (recorder.as_mut(), aggregator.last_date())
+                && let Err(err) = recorder.append(date, &frame) {
+                println!("Failed to record frame: {err}");
+            }
+            data_cnt += 1;
+            aggregate_time_total += start.elapsed().as_micros();
+        }
+        if scanner.dropped_frames() != dropped_frame_cnt {
+            dropped_frame_cnt = scanner.dropped_frames();
+            println!("Resynchronised after corrupted data ({} frame(s) dropped, {} byte(s) skipped so far)", dropped_frame_cnt, scanner.skipped_bytes());
+        }
+        if let Some(snapshotter) = snapshotter
+            && let Err(err) = snapshotter.save_if_due(aggregator) {
+            println!("Failed to save snapshot: {err}");
+        }
+        if let Some(date) = aggregator.last_date() {
+            update_status(state, |x| x.last_date = Some([date.year().cast_unsigned(), date.month(), date.day()]));
+        }
+        match reader.read(&mut chunk).await {
+            Ok(0) => break None,
+            Ok(n) => scanner.push(&chunk[..n]),
+            Err(err) => break Some(err.into()),
+        }
+    };
+    println!("Average Process Time: {} μs (Total {} ms)", aggregate_time_total / data_cnt.max(1) as u128, aggregate_time_total / 1000);
+    println!("Skipped {} frame(s), dropped {} corrupted frame(s), {} record(s) from unknown stations so far", skipped_frame_cnt, scanner.dropped_frames(), aggregator.unknown_records());
+    Ok(Session { frames: data_cnt, error })
+}
+
+// 再生サーバーに接続し、切れたら待ち時間を延ばしながらつなぎ直す
+// 集計はつなぎ直しても引き継ぐ
+pub(crate) async fn run(
+    state: Arc<AppState>,
+    mut aggregator: Aggregator,
+    mut recorder: Option<Recorder>,
+    mut snapshotter: Option<Snapshotter>,
+    resume: bool,
+) {
+    let mut backoff = INITIAL_BACKOFF;
+    loop {
+        let (error, frames) = match receive(&state, &mut aggregator, &mut recorder, &mut snapshotter, resume).await {
+            Ok(Session { frames, error }) => (error.map_or_else(|| "connection closed by server".to_string(), |x| x.to_string()), Some(frames)),
+            Err(err) => (err.to_string(), None),
+        };
+        // 1 フレームでも受け取れていれば、切れ方によらず待ち時間と失敗回数を最初に戻す
+        let received = frames.is_some_and(|x| x > 0);
+        if received {
+            backoff = INITIAL_BACKOFF;
+        }
+        // 切れたときにも書き出しておく
+        if let Some(snapshotter) = &mut snapshotter
+            && let Err(err) = snapshotter.save(&aggregator) {
+            println!("Failed to save snapshot: {err}");
+        }
+        println!("Upstream disconnected ({error}), retrying in {} ms", backoff.as_millis());
+        update_status(&state, |x| {
+            x.connected = false;
+            // 接続できなかったときだけ数える
+            if frames.is_none() {
+                x.failed_attempts += 1;
+            } else if received {
+                x.failed_attempts = 0;
+            }
+            x.last_error = Some(error);
+        });
+        tokio::time::sleep(backoff).await;
+        backoff = (backoff * 2).min(MAX_BACKOFF);
+    }
+}
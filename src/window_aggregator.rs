@@ -1,5 +1,5 @@
 use std::collections::BTreeMap;
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 use segtree::SegmentTree;
 use server::ObservationPointData;
 
@@ -9,9 +9,9 @@ type FloatSegTree = SegmentTree<f64, fn(&f64, &f64) -> f64>;
 
 #[derive(Serialize)]
 pub struct WindowAggregateResult {
-    max: f64,
-    min: f64,
-    avg: f64,
+    max: Option<f64>,
+    min: Option<f64>,
+    avg: Option<f64>,
 }
 
 impl TryFrom<&PointAggregator> for WindowAggregateResult {
@@ -22,19 +22,22 @@ impl TryFrom<&PointAggregator> for WindowAggregateResult {
             Err(())
         } else {
             Ok(Self {
-                max: value.max().unwrap(),
-                min: value.min().unwrap(),
-                avg: value.average().unwrap(),
+                max: value.max(),
+                min: value.min(),
+                avg: value.average(),
             })
         }
     }
 }
 
 struct PointAggregator {
-    records: [f64; WINDOW_SIZE],
+    // 欠測の日は None
+    records: [Option<f64>; WINDOW_SIZE],
     count: usize,
     index: usize,
     sum: f64,
+    // ウィンドウ内で平均気温がある日数
+    average_count: usize,
     max_seg: FloatSegTree,
     min_seg: FloatSegTree,
 }
@@ -42,51 +45,95 @@ struct PointAggregator {
 impl PointAggregator {
     pub fn new() -> Self {
         Self {
-            records: [0.0; WINDOW_SIZE],
+            records: [None; WINDOW_SIZE],
             count: 0,
             index: 0,
             sum: 0.0,
+            average_count: 0,
             max_seg: FloatSegTree::new(WINDOW_SIZE, |&a, &b| a.max(b), f64::MIN),
             min_seg: FloatSegTree::new(WINDOW_SIZE, |&a, &b| a.min(b), f64::MAX),
         }
     }
 
-    pub fn add(&mut self, min: f64, max: f64, avg: f64) {
-        self.sum -= self.records[self.index];
+    pub fn add(&mut self, min: Option<f64>, max: Option<f64>, avg: Option<f64>) {
+        if let Some(old) = self.records[self.index] {
+            self.sum -= old;
+            self.average_count -= 1;
+        }
         self.records[self.index] = avg;
-        self.sum += avg;
+        if let Some(avg) = avg {
+            self.sum += avg;
+            self.average_count += 1;
+        }
         self.count += 1;
-        self.max_seg.set(self.index, max);
-        self.min_seg.set(self.index, min);
+        self.max_seg.set(self.index, max.unwrap_or(f64::MIN));
+        self.min_seg.set(self.index, min.unwrap_or(f64::MAX));
         
         self.index = (self.index + 1) % WINDOW_SIZE;
     }
     
     pub fn max(&self) -> Option<f64> {
-        if self.count == 0 {
-            None
-        } else {
-            Some(self.max_seg.all_prod())
-        }
+        Some(self.max_seg.all_prod()).filter(|&x| x != f64::MIN)
     }
 
     pub fn min(&self) -> Option<f64> {
-        if self.count == 0 {
-            None
-        } else {
-            Some(self.min_seg.all_prod())
-        }
+        Some(self.min_seg.all_prod()).filter(|&x| x != f64::MAX)
     }
 
     pub fn average(&self) -> Option<f64> {
-        if self.count == 0 {
+        if self.average_count == 0 {
             None
         } else {
-            Some(self.sum / self.count.min(WINDOW_SIZE) as f64)
+            Some(self.sum / self.average_count as f64)
+        }
+    }
+}
+
+// 1 地点のウィンドウの中身（セグメント木は葉だけ持ち、復元時に組み立て直す）
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PointWindowSnapshot {
+    records: Vec<Option<f64>>,
+    max: Vec<f64>,
+    min: Vec<f64>,
+    count: usize,
+    index: usize,
+    // 足し引きを繰り返した値をそのまま残す（足し直すと丸め誤差で値が変わる）
+    sum: f64,
+}
+
+impl From<&PointAggregator> for PointWindowSnapshot {
+    fn from(value: &PointAggregator) -> Self {
+        Self {
+            records: value.records.to_vec(),
+            max: (0..WINDOW_SIZE).map(|i| value.max_seg.get(i)).collect(),
+            min: (0..WINDOW_SIZE).map(|i| value.min_seg.get(i)).collect(),
+            count: value.count,
+            index: value.index,
+            sum: value.sum,
         }
     }
 }
 
+impl TryFrom<PointWindowSnapshot> for PointAggregator {
+    type Error = anyhow::Error;
+
+    fn try_from(value: PointWindowSnapshot) -> Result<Self, Self::Error> {
+        anyhow::ensure!(value.max.len() == WINDOW_SIZE && value.min.len() == WINDOW_SIZE && value.index < WINDOW_SIZE,
+            "window size mismatch in snapshot");
+        let records: [Option<f64>; WINDOW_SIZE] = value.records.try_into()
+            .map_err(|_| anyhow::anyhow!("window size mismatch in snapshot"))?;
+        Ok(Self {
+            records,
+            count: value.count,
+            index: value.index,
+            sum: value.sum,
+            average_count: records.iter().flatten().count(),
+            max_seg: FloatSegTree::from(&value.max, |&a, &b| a.max(b), f64::MIN),
+            min_seg: FloatSegTree::from(&value.min, |&a, &b| a.min(b), f64::MAX),
+        })
+    }
+}
+
 pub struct WindowAggregator {
     points: BTreeMap<u32, PointAggregator>
 }
@@ -100,7 +147,7 @@ impl WindowAggregator {
 
     pub fn add(&mut self, data: &Vec<ObservationPointData>) {
         for point in data {
-            let aggr = self.points.entry(point.point_id()).or_insert_with(|| PointAggregator::new());
+            let aggr = self.points.entry(point.point_id()).or_insert_with(PointAggregator::new);
             aggr.add(point.min(), point.max(), point.average());
         }
     }
@@ -111,4 +158,16 @@ impl WindowAggregator {
             .map(|(k, v)| (*k, WindowAggregateResult::try_from(v).unwrap()) )
             .collect()
     }
+
+    pub fn snapshot(&self) -> BTreeMap<u32, PointWindowSnapshot> {
+        self.points.iter().map(|(k, v)| (*k, v.into())).collect()
+    }
+
+    pub fn restore(snapshot: BTreeMap<u32, PointWindowSnapshot>) -> anyhow::Result<Self> {
+        Ok(Self {
+            points: snapshot.into_iter()
+                .map(|(k, v)| Ok((k, v.try_into()?)))
+                .collect::<anyhow::Result<_>>()?,
+        })
+    }
 }
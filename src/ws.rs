@@ -1,4 +1,5 @@
-use crate::{AppState, Param};
+use crate::Param;
+use realtime_data_final::AppState;
 use axum::{
     extract::{State, WebSocketUpgrade, ws::WebSocket},
 };